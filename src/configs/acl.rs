@@ -0,0 +1,186 @@
+// ! Access control between peers, enforced by Center peers
+// Rules are compiled from current peers on every export, so they follow `add` and `rm`.
+// With no rules, Centers forward everything, otherwise traffic not allowed by a rule is dropped.
+
+use crate::configs::firewall::ForwardRule;
+use crate::configs::*;
+
+/// Group every peer belongs to
+pub const ALL_GROUP: &str = "all";
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Debug, AsRefStr, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    TCP,
+    UDP,
+    ICMP,
+}
+
+/// Traffic allowed by a rule, like "tcp/22,443"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Service {
+    pub protocol: Protocol,
+    /// Destination ports, any if empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+impl FromStr for Service {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Service, String> {
+        let (protocol, ports) = match text.split_once('/') {
+            Some((protocol, ports)) => (protocol, Some(ports)),
+            None => (text, None),
+        };
+        let protocol = match protocol.to_lowercase().as_str() {
+            "tcp" => Protocol::TCP,
+            "udp" => Protocol::UDP,
+            "icmp" => Protocol::ICMP,
+            _ => Err(format!(
+                "Unknown protocol {}, expected tcp, udp or icmp",
+                protocol
+            ))?,
+        };
+        let ports = ports
+            .into_iter()
+            .flat_map(|ports| ports.split(','))
+            .map(|port| u16::from_str(port).map_err(|_| format!("Port {} is weird.", port)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if protocol == Protocol::ICMP && !ports.is_empty() {
+            Err("ICMP has no ports")?;
+        }
+        Ok(Service { protocol, ports })
+    }
+}
+
+impl std::fmt::Display for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.protocol.as_ref().to_lowercase())?;
+        if !self.ports.is_empty() {
+            let ports = self.ports.iter().map(u16::to_string).collect::<Vec<_>>();
+            write!(f, "/{}", ports.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Peers of group `from` may connect to peers of group `to`.
+/// Groups are set by the Groups flag, name of a peer and "all" work as groups too.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AclRule {
+    pub from: String,
+    pub to: String,
+    /// Allowed traffic, everything if empty
+    #[serde(default)]
+    pub services: Vec<Service>,
+}
+
+impl std::fmt::Display for AclRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.from, self.to)?;
+        if self.services.is_empty() {
+            write!(f, " any")?;
+        }
+        for service in self.services.iter() {
+            write!(f, " {}", service)?;
+        }
+        Ok(())
+    }
+}
+
+impl PeerInfo {
+    /// Groups this peer belongs to, set by the Groups flag
+    pub fn groups(&self) -> &[String] {
+        self.flags
+            .iter()
+            .find_map(|f| match f {
+                PeerFlag::Groups { groups } => Some(groups.as_slice()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        group == ALL_GROUP || self.name == group || self.groups().iter().any(|g| g == group)
+    }
+}
+
+impl WireguardNetworkInfo {
+    /// Fails if a group of the rule has no peers
+    pub fn check_acl_rule(&self, rule: &AclRule) -> Result<(), String> {
+        for group in [&rule.from, &rule.to] {
+            if !self.peers.iter().any(|p| p.in_group(group)) {
+                Err(format!("No peer or group {}", group))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Addresses of peers in a group, along with networks routed through them
+    fn group_networks(&self, group: &str) -> Result<Vec<IpNetwork>, String> {
+        let mut networks = vec![];
+        for peer in self.peers.iter().filter(|p| p.in_group(group)) {
+            networks.append(&mut self.peer_networks(peer)?);
+            networks.extend(peer.routes().iter().cloned());
+        }
+        Ok(networks)
+    }
+
+    /// Forwarding rules a Center enforces, None if the network has no ACL
+    pub fn forward_rules(&self) -> Result<Option<Vec<ForwardRule>>, String> {
+        if self.acl.is_empty() {
+            return Ok(None);
+        }
+        self.acl
+            .iter()
+            .map(|rule| {
+                Ok(ForwardRule {
+                    sources: self.group_networks(&rule.from)?,
+                    destinations: self.group_networks(&rule.to)?,
+                    services: rule.services.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(Some)
+    }
+}
+
+#[test]
+fn test_acl() {
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    let mut laptop = test_peer("laptop", 2);
+    laptop.flags.push(PeerFlag::Groups {
+        groups: vec!["laptops".into()],
+    });
+    let mut server = test_peer("server", 3);
+    server.flags.push(PeerFlag::Groups {
+        groups: vec!["servers".into()],
+    });
+    let mut hub = test_peer("hub", 1);
+    hub.flags.push(PeerFlag::Center);
+    net.peers = vec![hub, laptop, server];
+    net.acl = vec![AclRule {
+        from: "laptops".into(),
+        to: "servers".into(),
+        services: vec![Service::from_str("tcp/22,443").unwrap()],
+    }];
+    assert_eq!(net.acl[0].to_string(), "laptops -> servers tcp/22,443");
+    assert!(net
+        .check_acl_rule(&AclRule {
+            from: "phones".into(),
+            to: ALL_GROUP.into(),
+            services: vec![],
+        })
+        .is_err());
+
+    let interface = net.map_to_interface(&net.peers[0]).unwrap();
+    let commands = interface.firewall.up_commands("wg0", &interface);
+    assert_eq!(
+        commands[2],
+        "iptables -A wg_bond_wg0 -s 10.0.0.2/32 -d 10.0.0.3/32 -p tcp -m multiport --dports 22,443 -j ACCEPT"
+    );
+    assert_eq!(commands[3], "iptables -A wg_bond_wg0 -j DROP");
+    assert!(net.map_to_interface(&net.peers[1]).unwrap().acl.is_none());
+}
@@ -0,0 +1,253 @@
+// ! CIDR arithmetic
+// Used to express routes as "everything except these networks".
+
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Network as a raw integer, so both families are handled the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RawNetwork {
+    /// Address length in bits, 32 or 128
+    bits: u8,
+    addr: u128,
+    prefix: u8,
+}
+
+impl RawNetwork {
+    fn from_network(net: IpNetwork) -> RawNetwork {
+        let raw = match net {
+            IpNetwork::V4(n) => RawNetwork {
+                bits: 32,
+                addr: u32::from(n.ip()) as u128,
+                prefix: n.prefix(),
+            },
+            IpNetwork::V6(n) => RawNetwork {
+                bits: 128,
+                addr: u128::from(n.ip()),
+                prefix: n.prefix(),
+            },
+        };
+        // drop host bits, so 10.0.0.1/24 becomes 10.0.0.0/24
+        RawNetwork {
+            addr: raw.addr & raw.mask(),
+            ..raw
+        }
+    }
+
+    fn to_network(self) -> IpNetwork {
+        match self.bits {
+            32 => IpNetwork::V4(
+                Ipv4Network::new(Ipv4Addr::from(self.addr as u32), self.prefix).unwrap(),
+            ),
+            _ => IpNetwork::V6(Ipv6Network::new(Ipv6Addr::from(self.addr), self.prefix).unwrap()),
+        }
+    }
+
+    fn host_bits(&self) -> u32 {
+        (self.bits - self.prefix) as u32
+    }
+
+    fn mask(&self) -> u128 {
+        (!0u128).checked_shl(self.host_bits()).unwrap_or(0)
+    }
+
+    fn contains(&self, other: &RawNetwork) -> bool {
+        self.bits == other.bits
+            && self.prefix <= other.prefix
+            && other.addr & self.mask() == self.addr
+    }
+
+    fn halves(&self) -> (RawNetwork, RawNetwork) {
+        let half = RawNetwork {
+            prefix: self.prefix + 1,
+            ..*self
+        };
+        (
+            half,
+            RawNetwork {
+                addr: self.addr | 1 << half.host_bits(),
+                ..half
+            },
+        )
+    }
+
+    /// Returns a network containing both this one and the next adjacent one of the same size
+    fn merge(&self, next: &RawNetwork) -> Option<RawNetwork> {
+        if self.bits != next.bits || self.prefix != next.prefix || self.prefix == 0 {
+            return None;
+        }
+        let parent = RawNetwork {
+            prefix: self.prefix - 1,
+            ..*self
+        };
+        if parent.addr & parent.mask() == self.addr && parent.halves().1 == *next {
+            Some(parent)
+        } else {
+            None
+        }
+    }
+
+    fn subtract(self, exclusion: &RawNetwork) -> Vec<RawNetwork> {
+        if exclusion.contains(&self) {
+            vec![]
+        } else if !self.contains(exclusion) {
+            vec![self]
+        } else {
+            let (low, high) = self.halves();
+            let mut result = low.subtract(exclusion);
+            result.append(&mut high.subtract(exclusion));
+            result
+        }
+    }
+}
+
+/// Merges given networks into the smallest list of networks covering the same addresses.
+pub fn aggregate(networks: &[IpNetwork]) -> Vec<IpNetwork> {
+    let mut raw = networks
+        .iter()
+        .map(|n| RawNetwork::from_network(*n))
+        .collect::<Vec<_>>();
+    raw.sort();
+
+    let mut merged: Vec<RawNetwork> = vec![];
+    for net in raw {
+        if merged.iter().any(|m| m.contains(&net)) {
+            continue;
+        }
+        merged.push(net);
+        while merged.len() >= 2 {
+            let last = merged[merged.len() - 1];
+            let prev = merged[merged.len() - 2];
+            match prev.merge(&last) {
+                Some(parent) => {
+                    merged.truncate(merged.len() - 2);
+                    merged.push(parent);
+                }
+                None => break,
+            }
+        }
+    }
+
+    merged.into_iter().map(RawNetwork::to_network).collect()
+}
+
+/// Returns networks covering every address in `networks`, except ones in `exclusions`.
+/// Result is aggregated to a minimal list of prefixes.
+pub fn exclude(networks: &[IpNetwork], exclusions: &[IpNetwork]) -> Vec<IpNetwork> {
+    let exclusions = exclusions
+        .iter()
+        .map(|n| RawNetwork::from_network(*n))
+        .collect::<Vec<_>>();
+
+    let remaining = exclusions.iter().fold(
+        networks
+            .iter()
+            .map(|n| RawNetwork::from_network(*n))
+            .collect::<Vec<_>>(),
+        |remaining, exclusion| {
+            remaining
+                .into_iter()
+                .flat_map(|net| net.subtract(exclusion))
+                .collect()
+        },
+    );
+
+    aggregate(
+        &remaining
+            .into_iter()
+            .map(RawNetwork::to_network)
+            .collect::<Vec<_>>(),
+    )
+}
+
+#[cfg(test)]
+fn parse_all(networks: &[&str]) -> Vec<IpNetwork> {
+    use std::str::FromStr;
+    networks
+        .iter()
+        .map(|n| IpNetwork::from_str(n).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_exclude_private_v4() {
+    // Table used before the exclusion was computed
+    assert_eq!(
+        exclude(
+            &parse_all(&["0.0.0.0/0"]),
+            &parse_all(&[
+                "10.0.0.0/8",
+                "172.16.0.0/12",
+                "192.168.0.0/16",
+                "224.0.0.0/3"
+            ])
+        ),
+        parse_all(&[
+            "0.0.0.0/5",
+            "8.0.0.0/7",
+            "11.0.0.0/8",
+            "12.0.0.0/6",
+            "16.0.0.0/4",
+            "32.0.0.0/3",
+            "64.0.0.0/2",
+            "128.0.0.0/3",
+            "160.0.0.0/5",
+            "168.0.0.0/6",
+            "172.0.0.0/12",
+            "172.32.0.0/11",
+            "172.64.0.0/10",
+            "172.128.0.0/9",
+            "173.0.0.0/8",
+            "174.0.0.0/7",
+            "176.0.0.0/4",
+            "192.0.0.0/9",
+            "192.128.0.0/11",
+            "192.160.0.0/13",
+            "192.169.0.0/16",
+            "192.170.0.0/15",
+            "192.172.0.0/14",
+            "192.176.0.0/12",
+            "192.192.0.0/10",
+            "193.0.0.0/8",
+            "194.0.0.0/7",
+            "196.0.0.0/6",
+            "200.0.0.0/5",
+            "208.0.0.0/4",
+        ])
+    );
+}
+
+#[test]
+fn test_exclude_v6() {
+    assert_eq!(
+        exclude(
+            &parse_all(&["::/0"]),
+            &parse_all(&["::/1", "c000::/2", "8000::/3", "a000::/4"])
+        ),
+        parse_all(&["b000::/4"])
+    );
+    // Host routes at the very start of the address space
+    assert_eq!(
+        exclude(&parse_all(&["::/126"]), &parse_all(&["::/128", "::1/128"])),
+        parse_all(&["::2/127"])
+    );
+    assert_eq!(
+        exclude(&parse_all(&["fd00::/8"]), &parse_all(&["fd00::1/128"])).len(),
+        120
+    );
+}
+
+#[test]
+fn test_aggregate() {
+    assert_eq!(
+        aggregate(&parse_all(&[
+            "10.0.1.0/24",
+            "10.0.0.0/24",
+            "10.0.2.0/23",
+            "10.0.2.5/32",
+            "fd00::/9",
+            "fd80::/9",
+        ])),
+        parse_all(&["10.0.0.0/22", "fd00::/8"])
+    );
+}
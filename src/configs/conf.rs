@@ -44,8 +44,14 @@ pub struct ConfFile {}
 impl ConfigType for ConfFile {
     type ExportConfig = ();
 
+    fn supports_acl() -> bool {
+        true
+    }
+
     fn write_config(config: WireguardConfiguration, _: ()) -> String {
         let interface = config.interface;
+        let pre_up = interface.pre_up_commands(&config.name);
+        let pre_down = interface.pre_down_commands(&config.name);
 
         let mut built = String::new();
         built.add_assign("[Interface]\n");
@@ -55,8 +61,8 @@ impl ConfigType for ConfFile {
         built.cfg_write_list("DNS", interface.dns);
         built.cfg_param_opt("ListenPort", interface.port);
         built.cfg_param_opt("Table", interface.table);
-        built.cfg_param_opt("PreUp", interface.pre_up);
-        built.cfg_param_opt("PreDown", interface.pre_down);
+        built.cfg_param_opt("PreUp", pre_up);
+        built.cfg_param_opt("PreDown", pre_down);
         built.cfg_param_opt("PostUp", interface.post_up);
         built.cfg_param_opt("PostDown", interface.post_down);
 
@@ -79,3 +85,184 @@ impl ConfigType for ConfFile {
         built
     }
 }
+
+/// Splits comma-separated list of a config value
+fn parse_list<T: std::str::FromStr>(value: &str) -> Result<Vec<T>, T::Err> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(T::from_str)
+        .collect()
+}
+
+/// Parses wg-quick (or `wg showconf`) config, inverse of [`ConfFile::write_config`].
+/// Returns parsed config, and keys which were not understood.
+pub fn parse_config(
+    name: &str,
+    text: &str,
+) -> Result<(WireguardConfiguration, Vec<String>), String> {
+    let mut interface = Interface {
+        private_key: String::new(),
+        address: vec![],
+        port: None,
+        dns: vec![],
+        fw_mark: None,
+        table: None,
+        pre_up: None,
+        post_up: None,
+        pre_down: None,
+        post_down: None,
+        masquerade: vec![],
+        forwarding: vec![],
+        firewall: Firewall::Iptables,
+        acl: None,
+    };
+    let mut peers: Vec<Peer> = vec![];
+    let mut ignored = vec![];
+
+    // Hooks may be given multiple times, wg-quick runs all of them
+    fn append_hook(hook: &mut Option<String>, value: &str) {
+        *hook = Some(match hook {
+            Some(existing) => format!("{};{}", existing, value),
+            None => value.to_string(),
+        })
+    }
+
+    let mut section = String::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_lowercase();
+            if section == "peer" {
+                peers.push(Peer {
+                    name: String::new(),
+                    public_key: String::new(),
+                    preshared_key: None,
+                    allowed_ips: vec![],
+                    endpoint: None,
+                    persistent_keepalive: None,
+                });
+            }
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .map(|(k, v)| (k.trim().to_lowercase(), v.trim()))
+            .ok_or(format!("Line {}: expected key = value", n + 1))?;
+        let error = |e: &dyn ToString| format!("Line {}: {}", n + 1, e.to_string());
+
+        match (section.as_str(), key.as_str()) {
+            ("interface", "privatekey") => interface.private_key = value.to_string(),
+            ("interface", "address") => interface.address.append(
+                &mut parse_list::<IpNetwork>(value)
+                    .map_err(|e| error(&e))?
+                    .iter()
+                    .map(IpNetwork::ip)
+                    .collect(),
+            ),
+            ("interface", "listenport") => {
+                interface.port = Some(u16::from_str(value).map_err(|e| error(&e))?)
+            }
+            ("interface", "dns") => {
+                for dns in value.split(',').map(str::trim) {
+                    match IpAddr::from_str(dns) {
+                        Ok(ip) => interface.dns.push(ip),
+                        Err(_) => ignored.push(format!("DNS = {}", dns)),
+                    }
+                }
+            }
+            ("interface", "fwmark") if value != "off" => {
+                let mark = match value.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => u32::from_str(value),
+                };
+                interface.fw_mark = Some(mark.map_err(|e| error(&e))?)
+            }
+            ("interface", "table") => interface.table = Some(value.to_string()),
+            ("interface", "preup") => append_hook(&mut interface.pre_up, value),
+            ("interface", "postup") => append_hook(&mut interface.post_up, value),
+            ("interface", "predown") => append_hook(&mut interface.pre_down, value),
+            ("interface", "postdown") => append_hook(&mut interface.post_down, value),
+            ("peer", _) => {
+                let peer = peers.last_mut().unwrap();
+                match key.as_str() {
+                    "publickey" => {
+                        peer.public_key = value.to_string();
+                        peer.name = value.to_string();
+                    }
+                    "presharedkey" => peer.preshared_key = Some(value.to_string()),
+                    "allowedips" => peer
+                        .allowed_ips
+                        .append(&mut parse_list(value).map_err(|e| error(&e))?),
+                    "endpoint" => peer.endpoint = Some(value.to_string()),
+                    "persistentkeepalive" if value != "off" => {
+                        peer.persistent_keepalive =
+                            Some(u16::from_str(value).map_err(|e| error(&e))?)
+                    }
+                    "persistentkeepalive" => {}
+                    _ => ignored.push(format!("[Peer] {} = {}", key, value)),
+                }
+            }
+            _ => ignored.push(format!("[{}] {} = {}", section, key, value)),
+        }
+    }
+
+    if let Some(peer) = peers.iter().find(|p| p.public_key.is_empty()) {
+        Err(format!(
+            "Peer with endpoint {:?} has no public key",
+            peer.endpoint
+        ))?;
+    }
+
+    Ok((
+        WireguardConfiguration {
+            interface,
+            peers,
+            name: name.to_string(),
+        },
+        ignored,
+    ))
+}
+
+#[test]
+fn test_parse_written_config() {
+    let text = "[Interface]
+PrivateKey = private
+Address = 10.0.0.2/24, fd00::2
+DNS = 10.0.0.1, example.com
+ListenPort = 51820
+MTU = 1420
+[Peer]
+PublicKey = public # server
+PresharedKey = psk
+Endpoint = example.com:51820
+PersistentKeepalive = 25
+AllowedIPs = 10.0.0.0/24, 0.0.0.0/0
+";
+    let (config, ignored) = parse_config("wg0", text).unwrap();
+    assert_eq!(ignored, ["DNS = example.com", "[interface] mtu = 1420"]);
+    assert_eq!(config.interface.address.len(), 2);
+    assert_eq!(config.peers[0].persistent_keepalive, Some(25));
+
+    assert_eq!(
+        ConfFile::write_config(config, ()),
+        "[Interface]
+PrivateKey = private
+Address = 10.0.0.2, fd00::2
+DNS = 10.0.0.1
+ListenPort = 51820
+[Peer]
+PublicKey = public
+PresharedKey = psk
+Endpoint = example.com:51820
+PersistentKeepalive = 25
+AllowedIPs = 10.0.0.0/24, 0.0.0.0/0
+"
+    );
+}
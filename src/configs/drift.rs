@@ -0,0 +1,274 @@
+// ! Comparison of generated configs with running interfaces
+// Accepts output of `wg showconf <if>`, `wg show <if> dump` or `wg show all dump`.
+
+use crate::configs::conf::parse_config;
+use crate::configs::*;
+use crate::wg_tools;
+
+fn dump_value(value: &str) -> Option<String> {
+    match value {
+        "(none)" | "off" | "0" => None,
+        _ => Some(value.to_string()),
+    }
+}
+
+/// Parses `wg show <if> dump` output. With `wg show all dump`, only lines of interface `name` are read.
+pub fn parse_dump(name: &str, text: &str) -> Result<WireguardConfiguration, String> {
+    let mut interface: Option<Interface> = None;
+    let mut peers = vec![];
+
+    for (n, line) in text.lines().enumerate() {
+        let mut fields = line.split('\t').collect::<Vec<_>>();
+        if fields.len() == 5 || fields.len() == 9 {
+            if fields[0] != name {
+                continue;
+            }
+            fields.remove(0);
+        }
+        let error = |e: &dyn ToString| format!("Line {}: {}", n + 1, e.to_string());
+
+        match (fields.len(), &interface) {
+            (4, None) => {
+                interface = Some(Interface {
+                    private_key: dump_value(fields[0]).unwrap_or_default(),
+                    address: vec![],
+                    port: dump_value(fields[2])
+                        .map(|p| u16::from_str(&p))
+                        .transpose()
+                        .map_err(|e| error(&e))?,
+                    dns: vec![],
+                    fw_mark: dump_value(fields[3])
+                        .map(|m| match m.strip_prefix("0x") {
+                            Some(hex) => u32::from_str_radix(hex, 16),
+                            None => u32::from_str(&m),
+                        })
+                        .transpose()
+                        .map_err(|e| error(&e))?,
+                    table: None,
+                    pre_up: None,
+                    post_up: None,
+                    pre_down: None,
+                    post_down: None,
+                    masquerade: vec![],
+                    forwarding: vec![],
+                    firewall: Firewall::Iptables,
+                    acl: None,
+                })
+            }
+            (8, Some(_)) => peers.push(Peer {
+                name: fields[0].to_string(),
+                public_key: fields[0].to_string(),
+                preshared_key: dump_value(fields[1]),
+                endpoint: dump_value(fields[2]),
+                allowed_ips: dump_value(fields[3])
+                    .map(|ips| {
+                        ips.split(',')
+                            .map(IpNetwork::from_str)
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .transpose()
+                    .map_err(|e| error(&e))?
+                    .unwrap_or_default(),
+                persistent_keepalive: dump_value(fields[7])
+                    .map(|k| u16::from_str(&k))
+                    .transpose()
+                    .map_err(|e| error(&e))?,
+            }),
+            _ => Err(error(&"unexpected number of fields"))?,
+        }
+    }
+
+    Ok(WireguardConfiguration {
+        interface: interface.ok_or(format!("No interface {} found in dump", name))?,
+        peers,
+        name: name.to_string(),
+    })
+}
+
+/// Parses state of an interface, in any of supported formats
+pub fn parse_state(name: &str, text: &str) -> Result<WireguardConfiguration, String> {
+    if text.contains("[Interface]") || text.contains("[Peer]") {
+        parse_config(name, text).map(|(config, _)| config)
+    } else {
+        parse_dump(name, text)
+    }
+}
+
+/// Whether endpoint in running interface matches a configured one.
+/// Domains are resolved by wg, so only their ports are compared.
+fn same_endpoint(expected: &str, actual: &str) -> bool {
+    match (
+        split_endpoint(expected.into()),
+        split_endpoint(actual.into()),
+    ) {
+        (Ok((Host::Domain(_), expected_port)), Ok((_, actual_port))) => {
+            expected_port == actual_port
+        }
+        (Ok(expected), Ok(actual)) => expected == actual,
+        _ => expected == actual,
+    }
+}
+
+fn list_networks(networks: &[IpNetwork]) -> String {
+    networks
+        .iter()
+        .map(IpNetwork::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Lists differences between generated config and state of a running interface.
+/// Keys of the interface are compared by `public_key`, so secrets of `expected` may be redacted.
+pub fn compare(
+    expected: &WireguardConfiguration,
+    public_key: &str,
+    actual: &WireguardConfiguration,
+) -> Vec<String> {
+    let mut differences = vec![];
+
+    if !actual.interface.private_key.is_empty() {
+        match wg_tools::gen_public_key(&actual.interface.private_key) {
+            Ok(actual_key) if actual_key == public_key => {}
+            Ok(actual_key) => differences.push(format!(
+                "Interface: public key is {}, expected {}",
+                actual_key, public_key
+            )),
+            Err(e) => differences.push(format!("Interface: weird private key, {}", e)),
+        }
+    }
+    // Interfaces without configured port listen on a random one
+    if expected.interface.port.is_some() && actual.interface.port != expected.interface.port {
+        differences.push(format!(
+            "Interface: listen port is {:?}, expected {:?}",
+            actual.interface.port, expected.interface.port
+        ));
+    }
+
+    for peer in expected.peers.iter() {
+        let found = match actual
+            .peers
+            .iter()
+            .find(|p| p.public_key == peer.public_key)
+        {
+            Some(found) => found,
+            None => {
+                differences.push(format!("{}: missing peer {}", peer.name, peer.public_key));
+                continue;
+            }
+        };
+
+        let mut expected_ips = peer.allowed_ips.clone();
+        let mut found_ips = found.allowed_ips.clone();
+        expected_ips.sort();
+        found_ips.sort();
+        if expected_ips != found_ips {
+            differences.push(format!(
+                "{}: AllowedIPs are {}, expected {}",
+                peer.name,
+                list_networks(&found.allowed_ips),
+                list_networks(&peer.allowed_ips)
+            ));
+        }
+
+        // Peers without configured endpoint may roam, so only configured ones are checked
+        if let Some(endpoint) = &peer.endpoint {
+            match &found.endpoint {
+                Some(found_endpoint) if same_endpoint(endpoint, found_endpoint) => {}
+                Some(found_endpoint) => differences.push(format!(
+                    "{}: endpoint is {}, expected {}",
+                    peer.name, found_endpoint, endpoint
+                )),
+                None => differences.push(format!(
+                    "{}: endpoint is not set, expected {}",
+                    peer.name, endpoint
+                )),
+            }
+        }
+
+        if found.persistent_keepalive != peer.persistent_keepalive {
+            differences.push(format!(
+                "{}: keepalive is {:?}, expected {:?}",
+                peer.name, found.persistent_keepalive, peer.persistent_keepalive
+            ));
+        }
+
+        match (&peer.preshared_key, &found.preshared_key) {
+            // Redacted key, only its presence is known
+            (Some(expected), Some(_)) if expected.is_empty() => {}
+            (expected, found) if expected != found => {
+                differences.push(format!("{}: preshared key differs", peer.name))
+            }
+            _ => {}
+        }
+    }
+
+    for peer in actual.peers.iter() {
+        if !expected
+            .peers
+            .iter()
+            .any(|p| p.public_key == peer.public_key)
+        {
+            differences.push(format!(
+                "Unknown peer {} with AllowedIPs {}",
+                peer.public_key,
+                list_networks(&peer.allowed_ips)
+            ));
+        }
+    }
+
+    differences
+}
+
+#[test]
+fn test_dump_drift() {
+    let (expected, _) = parse_config(
+        "wg0",
+        "[Interface]
+PrivateKey = private
+ListenPort = 51820
+[Peer]
+PublicKey = server
+Endpoint = example.com:51820
+AllowedIPs = 10.0.0.1/32, 10.0.1.0/24
+[Peer]
+PublicKey = laptop
+AllowedIPs = 10.0.0.2/32
+",
+    )
+    .unwrap();
+
+    let private_key = wg_tools::gen_private_key();
+    let public_key = wg_tools::gen_public_key(&private_key).unwrap();
+    let actual = parse_state(
+        "wg0",
+        &format!(
+            "wg0\t{}\t{}\t51820\toff
+wg0\tserver\t(none)\t1.2.3.4:51820\t10.0.1.0/24,10.0.0.1/32\t0\t0\t0\t25
+wg0\tphone\t(none)\t(none)\t10.0.0.3/32\t0\t0\t0\toff
+wg1\tother\t(none)\t(none)\t10.0.0.3/32\t0\t0\t0\toff
+",
+            private_key, public_key
+        ),
+    )
+    .unwrap();
+
+    assert_eq!(
+        compare(&expected, &public_key, &actual),
+        [
+            "server: keepalive is Some(25), expected None",
+            "laptop: missing peer laptop",
+            "Unknown peer phone with AllowedIPs 10.0.0.3/32",
+        ]
+    );
+    assert_eq!(
+        compare(&expected, "other", &actual)[0],
+        format!("Interface: public key is {}, expected other", public_key)
+    );
+
+    // Interfaces without a key are shown with (none)
+    let keyless = parse_dump("wg0", "(none)\t(none)\t51820\toff\n").unwrap();
+    assert!(keyless.interface.private_key.is_empty());
+    assert!(compare(&expected, &public_key, &keyless)
+        .iter()
+        .all(|d| !d.starts_with("Interface")));
+}
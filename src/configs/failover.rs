@@ -0,0 +1,172 @@
+// ! Failover script for peers with several hubs
+// WireGuard routes a network through one peer only, so the script moves network routes
+// to a backup hub when handshakes with the primary one go stale, and back once it recovers.
+// Peers accept traffic of other peers only from the hub they route through, so every peer
+// of the network should run the script, to switch together when the primary hub goes down.
+
+use crate::configs::*;
+
+/// Handshakes are renewed every two minutes while there is traffic
+pub const DEFAULT_STALE_AFTER: u64 = 180;
+
+/// Interface names wg-quick accepts, none of them needs quoting in the script
+fn check_interface_name(name: &str) -> Result<(), String> {
+    let valid = (1..=15).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_=+.-".contains(c));
+    if !valid {
+        Err(format!(
+            "Network name \"{}\" is not a valid interface name, rename it with `network --rename`",
+            name
+        ))?;
+    }
+    Ok(())
+}
+
+/// Shell script switching hubs of a peer, runs until killed
+pub fn write_script(
+    net: &WireguardNetworkInfo,
+    info: &PeerInfo,
+    stale_after: u64,
+) -> Result<String, String> {
+    if info.has_flag("Center") {
+        Err(format!("{} is a Center, it has no hubs", info.name))?;
+    }
+    check_interface_name(&net.name)?;
+    let hubs = net.hubs(info);
+    if hubs.len() < 2 {
+        Err("Failover needs at least two Center peers")?;
+    }
+    if !info.has_flag("Keepalive") {
+        warn!(
+            "{} has no keepalive, idle hubs will look unreachable",
+            info.name
+        );
+    }
+
+    // Base64 never contains @, so it separates keys from addresses
+    let entries = hubs
+        .iter()
+        .map(|hub| {
+            let addresses = net
+                .peer_networks(hub)?
+                .iter()
+                .map(IpNetwork::to_string)
+                .collect::<Vec<_>>();
+            Ok(format!("{}@{}", hub.public_key, addresses.join(",")))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let networks = net
+        .networks
+        .iter()
+        .map(IpNetwork::to_string)
+        .collect::<Vec<_>>();
+
+    let mut built = String::new();
+    built += "#!/bin/sh\n";
+    built += format!("# Hub failover of \"{}\" for {}\n", net.name, info.name).as_str();
+    built += "# Generated by wgbond\n";
+    built += format!(
+        "# Hubs, primary first: {}\n",
+        hubs.iter()
+            .map(|h| h.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+    .as_str();
+    built += format!("IF='{}'\n", net.name).as_str();
+    built += format!("NETWORKS='{}'\n", networks.join(",")).as_str();
+    built += format!("STALE={}\n", stale_after).as_str();
+    built += format!("HUBS='{}'\n", entries.join(" ")).as_str();
+    built += r#"
+fresh() {
+    last=$(wg show "$IF" latest-handshakes | awk -v key="$1" '$1 == key { print $2 }')
+    [ -n "$last" ] && [ "$last" -gt 0 ] && [ $(($(date +%s) - last)) -lt "$STALE" ]
+}
+
+active=""
+while :; do
+    chosen=""
+    for hub in $HUBS; do
+        if fresh "${hub%%@*}"; then
+            chosen=$hub
+            break
+        fi
+    done
+    # Routes stay where they are while no hub is reachable
+    if [ -n "$chosen" ] && [ "$chosen" != "$active" ]; then
+        for hub in $HUBS; do
+            if [ "$hub" = "$chosen" ]; then
+                wg set "$IF" peer "${hub%%@*}" allowed-ips "${hub#*@},$NETWORKS"
+            else
+                wg set "$IF" peer "${hub%%@*}" allowed-ips "${hub#*@}"
+            fi
+        done
+        active=$chosen
+        echo "Routing $NETWORKS through ${chosen%%@*}"
+    fi
+    sleep 10
+done
+"#;
+    Ok(built)
+}
+
+#[test]
+fn test_hubs() {
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.flags = vec![NetworkFlag::Centralized];
+    let mut hub_a = test_peer("hub-a", 1);
+    hub_a.flags.push(PeerFlag::Center);
+    let mut hub_b = test_peer("hub-b", 2);
+    hub_b.flags.push(PeerFlag::Center);
+    net.peers = vec![hub_a, hub_b, test_peer("laptop", 3), test_peer("phone", 4)];
+
+    fn allowed_ips(net: &WireguardNetworkInfo, viewer: usize) -> Vec<Vec<String>> {
+        net.get_configuration(&net.peers[viewer])
+            .unwrap()
+            .peers
+            .iter()
+            .map(|p| p.allowed_ips.iter().map(IpNetwork::to_string).collect())
+            .collect()
+    }
+    assert_eq!(
+        allowed_ips(&net, 2),
+        [vec!["10.0.0.0/24", "10.0.0.1/32"], vec!["10.0.0.2/32"]]
+    );
+    assert_eq!(
+        allowed_ips(&net, 0),
+        [
+            vec!["10.0.0.2/32"],
+            vec!["10.0.0.3/32"],
+            vec!["10.0.0.4/32"]
+        ]
+    );
+
+    // Both clients go through the same hub, which reaches each of them directly
+    net.flags.push(NetworkFlag::PrimaryHub { peer: 2 });
+    for client in [2, 3] {
+        assert_eq!(
+            allowed_ips(&net, client),
+            [vec!["10.0.0.1/32"], vec!["10.0.0.0/24", "10.0.0.2/32"]]
+        );
+    }
+    assert_eq!(
+        allowed_ips(&net, 1),
+        [
+            vec!["10.0.0.1/32"],
+            vec!["10.0.0.3/32"],
+            vec!["10.0.0.4/32"]
+        ]
+    );
+    let script = write_script(&net, &net.peers[2], DEFAULT_STALE_AFTER).unwrap();
+    assert!(script.contains(&format!(
+        "HUBS='{}@10.0.0.2/32 {}@10.0.0.1/32'",
+        net.peers[1].public_key, net.peers[0].public_key
+    )));
+    assert!(write_script(&net, &net.peers[0], DEFAULT_STALE_AFTER).is_err());
+
+    net.name = "x';reboot'".into();
+    assert!(write_script(&net, &net.peers[2], DEFAULT_STALE_AFTER).is_err());
+}
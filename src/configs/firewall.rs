@@ -0,0 +1,340 @@
+// ! Firewall rules set up by interface hooks
+// Rules are idempotent, so bringing an interface up twice doesn't duplicate them.
+// nftables rules live in a table of their own, which is replaced as a whole.
+// iptables filtering rules live in a chain of their own, masquerade rules are checked before adding.
+
+use crate::configs::acl::{Protocol, Service};
+use crate::configs::*;
+
+/// Tool firewall rules are set up with
+#[derive(Serialize, Deserialize, Debug, AsRefStr, Clone, Copy, PartialEq, Default)]
+pub enum Firewall {
+    #[default]
+    Iptables,
+    Nftables,
+}
+
+impl FromStr for Firewall {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Firewall, String> {
+        match name {
+            "iptables" => Ok(Firewall::Iptables),
+            "nftables" => Ok(Firewall::Nftables),
+            _ => Err(format!(
+                "Unknown firewall {}, expected iptables or nftables",
+                name
+            )),
+        }
+    }
+}
+
+/// Traffic between peers a host forwards
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardRule {
+    pub sources: Vec<IpNetwork>,
+    pub destinations: Vec<IpNetwork>,
+    /// Allowed traffic, everything if empty
+    pub services: Vec<Service>,
+}
+
+/// Name of nftables table, or iptables chain, with rules of an interface
+pub fn table_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    format!("wg_bond_{}", name)
+}
+
+/// Commands enabling forwarding for address families of forwarded networks
+fn forwarding_commands(interface: &Interface) -> Vec<String> {
+    let mut commands = vec![];
+    if interface.forwarding.iter().any(IpNetwork::is_ipv4) {
+        commands.push("sysctl -w net.ipv4.ip_forward=1".to_string())
+    }
+    if interface.forwarding.iter().any(IpNetwork::is_ipv6) {
+        commands.push("sysctl -w net.ipv6.conf.all.forwarding=1".to_string())
+    }
+    commands
+}
+
+fn iptables_rules(interface: &Interface) -> Vec<(&'static str, String)> {
+    interface
+        .masquerade
+        .iter()
+        .flat_map(|m| {
+            m.sources.iter().map(move |source| {
+                let command = if source.is_ipv4() {
+                    "iptables"
+                } else {
+                    "ip6tables"
+                };
+                let rule = format!("POSTROUTING -s {} -o {} -j MASQUERADE", source, m.interface);
+                (command, rule)
+            })
+        })
+        .collect()
+}
+
+fn nft_rules(interface: &Interface) -> Vec<String> {
+    interface
+        .masquerade
+        .iter()
+        .flat_map(|m| {
+            m.sources.iter().map(move |source| {
+                let family = if source.is_ipv4() { "ip" } else { "ip6" };
+                format!(
+                    "{} saddr {} oifname {} masquerade",
+                    family, source, m.interface
+                )
+            })
+        })
+        .collect()
+}
+
+/// Addresses of a family, joined with `separator`, None if there are none
+fn join_family(networks: &[IpNetwork], ipv4: bool, separator: &str) -> Option<String> {
+    let networks = networks
+        .iter()
+        .filter(|n| n.is_ipv4() == ipv4)
+        .map(IpNetwork::to_string)
+        .collect::<Vec<_>>();
+    if networks.is_empty() {
+        None
+    } else {
+        Some(networks.join(separator))
+    }
+}
+
+/// Address families used by an interface, as iptables commands and whether they are IPv4
+fn iptables_families(interface: &Interface) -> Vec<(&'static str, bool)> {
+    vec![("iptables", true), ("ip6tables", false)]
+        .into_iter()
+        .filter(|(_, ipv4)| interface.address.iter().any(|a| a.is_ipv4() == *ipv4))
+        .collect()
+}
+
+/// Commands filling filtering chain of interface `name` with forwarding rules
+fn iptables_acl(name: &str, acl: &[ForwardRule], interface: &Interface) -> Vec<String> {
+    let chain = table_name(name);
+    let mut commands = vec![];
+    for (command, ipv4) in iptables_families(interface) {
+        commands.push(format!(
+            "{0} -N {1} 2>/dev/null || {0} -F {1}",
+            command, chain
+        ));
+        commands.push(format!(
+            "{} -A {} -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT",
+            command, chain
+        ));
+        for rule in acl {
+            let (sources, destinations) = match (
+                join_family(&rule.sources, ipv4, ","),
+                join_family(&rule.destinations, ipv4, ","),
+            ) {
+                (Some(sources), Some(destinations)) => (sources, destinations),
+                _ => continue,
+            };
+            let matches = match rule.services.is_empty() {
+                true => vec![String::new()],
+                false => rule
+                    .services
+                    .iter()
+                    .map(
+                        |service| match (service.protocol, service.ports.is_empty()) {
+                            (Protocol::ICMP, _) if ipv4 => " -p icmp".to_string(),
+                            (Protocol::ICMP, _) => " -p ipv6-icmp".to_string(),
+                            (protocol, true) => format!(" -p {}", protocol.as_ref().to_lowercase()),
+                            (protocol, false) => format!(
+                                " -p {} -m multiport --dports {}",
+                                protocol.as_ref().to_lowercase(),
+                                service
+                                    .ports
+                                    .iter()
+                                    .map(u16::to_string)
+                                    .collect::<Vec<_>>()
+                                    .join(",")
+                            ),
+                        },
+                    )
+                    .collect(),
+            };
+            for service in matches {
+                commands.push(format!(
+                    "{} -A {} -s {} -d {}{} -j ACCEPT",
+                    command, chain, sources, destinations, service
+                ));
+            }
+        }
+        commands.push(format!("{} -A {} -j DROP", command, chain));
+        commands.push(format!(
+            "{0} -C FORWARD -i {1} -o {1} -j {2} 2>/dev/null || {0} -I FORWARD -i {1} -o {1} -j {2}",
+            command, name, chain
+        ));
+    }
+    commands
+}
+
+/// Rules of nftables forward chain of interface `name`
+fn nft_acl(name: &str, acl: &[ForwardRule]) -> Vec<String> {
+    let between = format!("iifname {0} oifname {0}", name);
+    let mut rules = vec![format!("{} ct state established,related accept", between)];
+    for rule in acl {
+        for (family, ipv4) in [("ip", true), ("ip6", false)] {
+            let (sources, destinations) = match (
+                join_family(&rule.sources, ipv4, ", "),
+                join_family(&rule.destinations, ipv4, ", "),
+            ) {
+                (Some(sources), Some(destinations)) => (sources, destinations),
+                _ => continue,
+            };
+            let addresses = format!(
+                "{0} saddr {{ {1} }} {0} daddr {{ {2} }}",
+                family, sources, destinations
+            );
+            let matches = match rule.services.is_empty() {
+                true => vec![String::new()],
+                false => rule
+                    .services
+                    .iter()
+                    .map(
+                        |service| match (service.protocol, service.ports.is_empty()) {
+                            (Protocol::ICMP, _) if ipv4 => " meta l4proto icmp".to_string(),
+                            (Protocol::ICMP, _) => " meta l4proto ipv6-icmp".to_string(),
+                            (protocol, true) => {
+                                format!(" meta l4proto {}", protocol.as_ref().to_lowercase())
+                            }
+                            (protocol, false) => format!(
+                                " {} dport {{ {} }}",
+                                protocol.as_ref().to_lowercase(),
+                                service
+                                    .ports
+                                    .iter()
+                                    .map(u16::to_string)
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                        },
+                    )
+                    .collect(),
+            };
+            for service in matches {
+                rules.push(format!("{} {}{} accept", between, addresses, service));
+            }
+        }
+    }
+    rules.push(format!("{} drop", between));
+    rules
+}
+
+impl Firewall {
+    /// Commands setting up forwarding and masquerade of interface `name`
+    pub fn up_commands(&self, name: &str, interface: &Interface) -> Vec<String> {
+        let mut commands = forwarding_commands(interface);
+        match self {
+            Firewall::Iptables => {
+                for (command, rule) in iptables_rules(interface) {
+                    commands.push(format!(
+                        "{0} -t nat -C {1} 2>/dev/null || {0} -t nat -A {1}",
+                        command, rule
+                    ))
+                }
+                if let Some(acl) = &interface.acl {
+                    commands.append(&mut iptables_acl(name, acl, interface));
+                }
+            }
+            Firewall::Nftables => {
+                let mut chains = vec![];
+                let masquerade = nft_rules(interface);
+                if !masquerade.is_empty() {
+                    chains.push((
+                        "postrouting",
+                        "nat hook postrouting priority 100",
+                        masquerade,
+                    ));
+                }
+                if let Some(acl) = &interface.acl {
+                    chains.push((
+                        "forward",
+                        "filter hook forward priority 0",
+                        nft_acl(name, acl),
+                    ));
+                }
+                if !chains.is_empty() {
+                    let table = format!("inet {}", table_name(name));
+                    // Declaring the table first makes flush work when it doesn't exist yet
+                    let mut script = format!("table {0} {{}}\\nflush table {0}\\n", table);
+                    script += format!("table {} {{\\n", table).as_str();
+                    for (chain, kind, rules) in chains {
+                        script += format!("\\tchain {} {{\\n", chain).as_str();
+                        script += format!("\\t\\ttype {}\\n", kind).as_str();
+                        for rule in rules {
+                            script += format!("\\t\\t{}\\n", rule).as_str();
+                        }
+                        script += "\\t}\\n";
+                    }
+                    script += "}\\n";
+                    commands.push(format!("printf '{}' | nft -f -", script));
+                }
+            }
+        }
+        commands
+    }
+
+    /// Commands removing rules set up by [`Firewall::up_commands`]. Forwarding is left enabled,
+    /// as other things may rely on it.
+    pub fn down_commands(&self, name: &str, interface: &Interface) -> Vec<String> {
+        match self {
+            Firewall::Iptables => {
+                let mut commands = iptables_rules(interface)
+                    .into_iter()
+                    .map(|(command, rule)| {
+                        format!("{} -t nat -D {} 2>/dev/null || true", command, rule)
+                    })
+                    .collect::<Vec<_>>();
+                if interface.acl.is_some() {
+                    let chain = table_name(name);
+                    for (command, _) in iptables_families(interface) {
+                        commands.push(format!(
+                            "{0} -D FORWARD -i {1} -o {1} -j {2} 2>/dev/null || true",
+                            command, name, chain
+                        ));
+                        commands.push(format!("{} -F {} 2>/dev/null || true", command, chain));
+                        commands.push(format!("{} -X {} 2>/dev/null || true", command, chain));
+                    }
+                }
+                commands
+            }
+            Firewall::Nftables if interface.masquerade.is_empty() && interface.acl.is_none() => {
+                vec![]
+            }
+            Firewall::Nftables => vec![format!(
+                "nft delete table inet {} 2>/dev/null || true",
+                table_name(name)
+            )],
+        }
+    }
+}
+
+#[test]
+fn test_firewall_commands() {
+    let mut interface = PeerInfo::derive_interface(&test_peer("router", 1)).unwrap();
+    interface.masquerade = vec![Masquerade {
+        interface: "eth0".into(),
+        sources: vec![IpNetwork::from_str("10.0.0.0/24").unwrap()],
+    }];
+
+    assert_eq!(
+        Firewall::Iptables.up_commands("wg0", &interface),
+        ["iptables -t nat -C POSTROUTING -s 10.0.0.0/24 -o eth0 -j MASQUERADE 2>/dev/null || iptables -t nat -A POSTROUTING -s 10.0.0.0/24 -o eth0 -j MASQUERADE"]
+    );
+    assert_eq!(
+        Firewall::Nftables.up_commands("wg-0", &interface),
+        ["printf 'table inet wg_bond_wg_0 {}\\nflush table inet wg_bond_wg_0\\ntable inet wg_bond_wg_0 {\\n\\tchain postrouting {\\n\\t\\ttype nat hook postrouting priority 100\\n\\t\\tip saddr 10.0.0.0/24 oifname eth0 masquerade\\n\\t}\\n}\\n' | nft -f -"]
+    );
+    assert_eq!(
+        Firewall::Nftables.down_commands("wg-0", &interface),
+        ["nft delete table inet wg_bond_wg_0 2>/dev/null || true"]
+    );
+}
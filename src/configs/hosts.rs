@@ -7,16 +7,11 @@ pub fn export_hosts(cfg: &configs::WireguardNetworkInfo) -> Result<String, Strin
     built += format!("# Hosts for Wireguard network \"{}\"\n", cfg.name).as_str();
     built += "# Generated by wgbond\n";
     for peer in cfg.peers.iter() {
-        let wg_peer = cfg.map_to_interface(peer)?;
+        let addresses = cfg.peer_addresses(peer)?;
         built += format!(
             "{ip}\t{name}.{network}\n",
             name = peer.name,
-            ip = wg_peer
-                .address
-                .iter()
-                .map(|a| a.to_string())
-                .next()
-                .unwrap(),
+            ip = addresses.iter().map(|a| a.to_string()).next().unwrap(),
             network = cfg.name
         )
         .as_str();
@@ -0,0 +1,511 @@
+// ! Import of existing wg-quick configs
+// Peers are matched across files by their public keys, and ids are recovered from addresses.
+
+use crate::configs::conf::parse_config;
+use crate::configs::*;
+
+/// Peer built from one of the imported files, or from peer entries of them
+struct ImportedPeer {
+    info: PeerInfo,
+    public_key: String,
+    /// Peer entries of its config, none if only other configs know the peer
+    peers: Vec<Peer>,
+}
+
+/// Something learned about an imported peer from config of another one
+enum Update {
+    /// Peer entry describing a target peer
+    Seen {
+        viewer: usize,
+        target: usize,
+        peer: Peer,
+    },
+    /// Viewer keeps connections alive
+    Keepalive { viewer: usize, keepalive: u16 },
+}
+
+/// Tries to recognize masquerade commands produced by the Masquerade flag,
+/// returns the interface and firewall they are set up with
+fn parse_masquerade(command: &str) -> Option<(String, Firewall)> {
+    let words = command.split_whitespace().collect::<Vec<_>>();
+    let after = |word: &str| {
+        words
+            .iter()
+            .position(|w| *w == word)
+            .and_then(|i| words.get(i + 1))
+            .map(|i| i.to_string())
+    };
+    if words.contains(&"MASQUERADE") && words.contains(&"POSTROUTING") {
+        after("-o").map(|i| (i, Firewall::Iptables))
+    } else if words.contains(&"nft") && words.contains(&"masquerade") {
+        after("oifname").map(|i| (i, Firewall::Nftables))
+    } else {
+        None
+    }
+}
+
+/// Whether a command is one enabling forwarding, or removing an nftables table of wg-bond
+fn is_generated(command: &str) -> bool {
+    let command = command.trim();
+    command == "sysctl -w net.ipv4.ip_forward=1"
+        || command == "sysctl -w net.ipv6.conf.all.forwarding=1"
+        || command.starts_with("nft delete table inet wg_bond_")
+}
+
+/// Reads peer info from interface section of a config
+fn import_interface(
+    net: &WireguardNetworkInfo,
+    name: &str,
+    config: WireguardConfiguration,
+    report: &mut Vec<String>,
+) -> Result<ImportedPeer, String> {
+    let interface = &config.interface;
+    let public_key = wg_tools::gen_public_key(&interface.private_key)?;
+
+    let ids = interface
+        .address
+        .iter()
+        .map(|address| {
+            net.networks
+                .iter()
+                .find_map(|n| get_address_id(*n, *address))
+                .ok_or(format!("address {} is outside of the network", address))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let id = match ids.split_first() {
+        None => Err("no addresses")?,
+        Some((id, rest)) if rest.iter().all(|other| other == id) => *id,
+        _ => Err("addresses have different ids in their networks")?,
+    };
+
+    let mut flags = vec![];
+    if !interface.dns.is_empty() {
+        flags.push(PeerFlag::DNS {
+            addresses: interface.dns.clone(),
+        })
+    }
+
+    for (hook, value) in [
+        ("PreUp", &interface.pre_up),
+        ("PostUp", &interface.post_up),
+        ("PreDown", &interface.pre_down),
+        ("PostDown", &interface.post_down),
+    ] {
+        for command in value.iter().flat_map(|v| v.split(';')) {
+            if is_generated(command) {
+                continue;
+            }
+            match parse_masquerade(command) {
+                Some((if_name, backend)) if hook == "PreUp" || hook == "PostUp" => {
+                    flags.push(PeerFlag::Masquerade { interface: if_name });
+                    if backend != Firewall::Iptables {
+                        flags.push(PeerFlag::Firewall { backend })
+                    }
+                }
+                Some(_) => {}
+                None => report.push(format!(
+                    "{}: {} hook is not imported: {}",
+                    name, hook, command
+                )),
+            }
+        }
+    }
+    if let Some(table) = &interface.table {
+        report.push(format!("{}: Table = {} is not imported", name, table));
+    }
+    if let Some(mark) = &interface.fw_mark {
+        report.push(format!("{}: FwMark = {} is not imported", name, mark));
+    }
+
+    let info = PeerInfo {
+        name: name.to_string(),
+        private_key: Some(interface.private_key.clone().into()),
+        public_key: public_key.clone(),
+        id,
+        flags,
+        endpoints: vec![],
+        listen_port: interface.port,
+        addresses: vec![],
+        key_created: None,
+        key_history: vec![],
+    };
+    // Network and broadcast addresses map to ids no peer can have
+    net.peer_networks(&info)?;
+    Ok(ImportedPeer {
+        info,
+        public_key,
+        peers: config.peers,
+    })
+}
+
+/// Peer with only a public key, from a peer entry of another config.
+/// Its id is recovered from single addresses among AllowedIPs.
+fn import_public_key(net: &WireguardNetworkInfo, peer: &Peer) -> Result<ImportedPeer, String> {
+    let ids = peer
+        .allowed_ips
+        .iter()
+        .filter(|ip| ip.prefix() == if ip.is_ipv4() { 32 } else { 128 })
+        .filter_map(|ip| {
+            net.networks
+                .iter()
+                .find_map(|n| get_address_id(*n, ip.ip()))
+        })
+        .collect::<Vec<_>>();
+    let id = match ids.split_first() {
+        None => Err("no addresses in the network")?,
+        Some((id, rest)) if rest.iter().all(|other| other == id) => *id,
+        _ => Err("addresses have different ids in their networks")?,
+    };
+    let info = PeerInfo {
+        name: format!("peer{}", id),
+        private_key: None,
+        public_key: peer.public_key.clone(),
+        id,
+        flags: vec![],
+        endpoints: vec![],
+        listen_port: None,
+        addresses: vec![],
+        key_created: None,
+        key_history: vec![],
+    };
+    net.peer_networks(&info)?;
+    Ok(ImportedPeer {
+        info,
+        public_key: peer.public_key.clone(),
+        peers: vec![],
+    })
+}
+
+/// Reason a peer can't be added along with already imported ones
+fn conflict(
+    net: &WireguardNetworkInfo,
+    imported: &[ImportedPeer],
+    peer: &ImportedPeer,
+) -> Option<String> {
+    let mut existing = net.peers.iter().chain(imported.iter().map(|p| &p.info));
+    if let Some(other) = existing
+        .clone()
+        .find(|p| p.name == peer.info.name || p.id == peer.info.id)
+    {
+        return Some(format!(
+            "conflicts with peer {} (#{})",
+            other.name, other.id
+        ));
+    }
+    existing
+        .find(|p| p.public_key == peer.public_key)
+        .map(|other| format!("already in the network as {}", other.name))
+}
+
+/// Imports peers from wg-quick configs, given as file names and contents.
+/// Returns a report of things which could not be imported.
+pub fn import_configs(
+    net: &mut WireguardNetworkInfo,
+    files: &[(String, String)],
+) -> Result<Vec<String>, String> {
+    let mut report = vec![];
+    let mut imported: Vec<ImportedPeer> = vec![];
+
+    for (name, text) in files {
+        let (config, ignored) =
+            parse_config(&net.name, text).map_err(|e| format!("{}: {}", name, e))?;
+        for key in ignored {
+            report.push(format!("{}: {} is not imported", name, key));
+        }
+
+        let peer = match import_interface(net, name, config, &mut report) {
+            Ok(peer) => peer,
+            Err(e) => {
+                report.push(format!("{}: skipped, {}", name, e));
+                continue;
+            }
+        };
+
+        match conflict(net, &imported, &peer) {
+            Some(e) => report.push(format!("{}: skipped, {}", name, e)),
+            None => imported.push(peer),
+        }
+    }
+
+    // Peers without a config of their own are known only by their public keys
+    let entries = imported
+        .iter()
+        .flat_map(|viewer| {
+            viewer
+                .peers
+                .iter()
+                .map(move |peer| (viewer.info.name.clone(), peer.clone()))
+        })
+        .collect::<Vec<_>>();
+    for (viewer, peer) in entries {
+        if imported.iter().any(|p| p.public_key == peer.public_key) {
+            continue;
+        }
+        let result = import_public_key(net, &peer)
+            .and_then(|p| conflict(net, &imported, &p).map_or(Ok(p), Err));
+        match result {
+            Ok(p) => {
+                report.push(format!(
+                    "{}: peer {} has no config, imported with only a public key as {}",
+                    viewer, peer.public_key, p.info.name
+                ));
+                imported.push(p);
+            }
+            Err(e) => report.push(format!(
+                "{}: peer {} is skipped, {}",
+                viewer, peer.public_key, e
+            )),
+        }
+    }
+
+    let by_key = |public_key: &str| imported.iter().position(|p| p.public_key == public_key);
+    let mut preshared_keys = vec![];
+    let mut updates = vec![];
+
+    for (viewer_index, viewer) in imported.iter().enumerate() {
+        for peer in viewer.peers.iter() {
+            // Skipped peers were reported above
+            let target = match by_key(&peer.public_key) {
+                Some(target) => target,
+                None => continue,
+            };
+            updates.push(Update::Seen {
+                viewer: viewer_index,
+                target,
+                peer: peer.clone(),
+            });
+
+            if let Some(keepalive) = peer.persistent_keepalive {
+                updates.push(Update::Keepalive {
+                    viewer: viewer_index,
+                    keepalive,
+                });
+            }
+
+            if let Some(psk) = &peer.preshared_key {
+                preshared_keys.push(PresharedKey {
+                    peers: peer_pair(viewer.info.id, imported[target].info.id),
+                    key: psk.clone().into(),
+                })
+            }
+        }
+    }
+
+    for update in updates {
+        let (viewer, target, peer) = match update {
+            Update::Seen {
+                viewer,
+                target,
+                peer,
+            } => (imported[viewer].info.name.clone(), target, peer),
+            Update::Keepalive { viewer, keepalive } => {
+                let viewer = &mut imported[viewer].info;
+                match viewer.flags.iter().find_map(|f| match f {
+                    PeerFlag::Keepalive { keepalive } => Some(*keepalive),
+                    _ => None,
+                }) {
+                    Some(existing) if existing != keepalive => report.push(format!(
+                        "{}: different keepalive intervals used, keeping {}",
+                        viewer.name, existing
+                    )),
+                    Some(_) => {}
+                    None => viewer.flags.push(PeerFlag::Keepalive { keepalive }),
+                }
+                continue;
+            }
+        };
+        let peer_networks = net.peer_networks(&imported[target].info)?;
+        let target = &mut imported[target].info;
+        let name = target.name.clone();
+
+        match (target.endpoint(None), &peer.endpoint) {
+            (None, Some(endpoint)) => match check_endpoint(endpoint.clone()) {
+                Ok(address) => target.set_endpoint(Endpoint {
+                    name: DEFAULT_ENDPOINT.into(),
+                    address,
+                }),
+                Err(e) => report.push(format!("{}: endpoint {} skipped, {}", name, endpoint, e)),
+            },
+            (Some(existing), Some(endpoint)) if existing != endpoint => report.push(format!(
+                "{}: also known as {}, keeping {}",
+                name, endpoint, existing
+            )),
+            _ => {}
+        }
+
+        let mut extra = peer
+            .allowed_ips
+            .iter()
+            .filter(|ip| !peer_networks.contains(ip))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !net.networks.is_empty() && net.networks.iter().all(|n| extra.contains(n)) {
+            if !target.has_flag("Center") {
+                target.flags.push(PeerFlag::Center);
+                report.push(format!(
+                    "{}: marked as Center, {} routes the whole network through it",
+                    name, viewer
+                ));
+            }
+            extra.retain(|ip| !net.networks.contains(ip));
+        }
+        if extra.iter().any(|ip| ip.prefix() == 0) {
+            if !target.has_flag("Gateway") {
+                target.flags.push(PeerFlag::Gateway {
+                    ignore_local_networks: false,
+                    exclude: vec![],
+                });
+                report.push(format!(
+                    "{}: marked as Gateway, {} routes the internet through it",
+                    name, viewer
+                ));
+            }
+            extra.retain(|ip| ip.prefix() != 0);
+        }
+        let routes = target.routes();
+        extra.retain(|ip| !routes.contains(ip));
+        if !extra.is_empty() {
+            report.push(format!(
+                "{}: routes {} added, {} routes them through it",
+                name,
+                extra
+                    .iter()
+                    .map(IpNetwork::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                viewer
+            ));
+            match target.flags.iter_mut().find_map(|f| match f {
+                PeerFlag::Routes { networks, .. } => Some(networks),
+                _ => None,
+            }) {
+                Some(networks) => networks.append(&mut extra),
+                None => target.flags.push(PeerFlag::Routes {
+                    networks: extra,
+                    masquerade: None,
+                }),
+            }
+        }
+    }
+
+    if !preshared_keys.is_empty() {
+        if !net.has_flag("PresharedKeys") {
+            net.flags.push(NetworkFlag::PresharedKeys);
+            report.push("Preshared keys enabled, missing ones will be generated".into());
+        }
+        for psk in preshared_keys {
+            if !net.preshared_keys.iter().any(|p| p.peers == psk.peers) {
+                net.preshared_keys.push(psk)
+            }
+        }
+    }
+
+    report.push(format!("Imported {} peers", imported.len()));
+    for mut peer in imported {
+        peer.info.flags.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        peer.info.flags.dedup_by(|a, b| a.as_ref() == b.as_ref());
+        if let Err(e) = net.check_routes(&peer.info) {
+            report.push(format!(
+                "{}: routes are not imported, {}",
+                peer.info.name, e
+            ));
+            peer.info.flags.retain(|f| f.as_ref() != "Routes");
+        }
+        net.peers.push(peer.info);
+    }
+
+    Ok(report)
+}
+
+#[test]
+fn test_import() {
+    let server_key = wg_tools::gen_private_key();
+    let client_key = wg_tools::gen_private_key();
+    let server = format!(
+        "[Interface]
+PrivateKey = {}
+Address = 10.0.0.1/24
+ListenPort = 51820
+PostUp = iptables -A POSTROUTING -t nat -j MASQUERADE -s 10.0.0.0/24 -o eth0
+[Peer]
+PublicKey = {}
+AllowedIPs = 10.0.0.5/32
+",
+        server_key,
+        wg_tools::gen_public_key(&client_key).unwrap()
+    );
+    let client = format!(
+        "[Interface]
+PrivateKey = {}
+Address = 10.0.0.5/32
+[Peer]
+PublicKey = {}
+Endpoint = example.com:51820
+PersistentKeepalive = 25
+AllowedIPs = 10.0.0.0/24, 192.168.0.0/24, 0.0.0.0/0
+[Peer]
+PublicKey = {}
+AllowedIPs = 10.0.0.7/32
+",
+        client_key,
+        wg_tools::gen_public_key(&server_key).unwrap(),
+        wg_tools::gen_public_key(&wg_tools::gen_private_key()).unwrap(),
+    );
+
+    let broadcast = format!(
+        "[Interface]
+PrivateKey = {}
+Address = 10.0.0.255/24
+",
+        wg_tools::gen_private_key()
+    );
+
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    let report = import_configs(
+        &mut net,
+        &[
+            ("server".into(), server),
+            ("broadcast".into(), broadcast),
+            ("client".into(), client),
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(report.len(), 6);
+    assert!(report[0].starts_with("broadcast: skipped, "));
+    assert!(report[1].ends_with("imported with only a public key as peer7"));
+    assert_eq!(
+        report[2],
+        "server: marked as Center, client routes the whole network through it"
+    );
+    assert_eq!(
+        report[3],
+        "server: marked as Gateway, client routes the internet through it"
+    );
+    assert_eq!(
+        report[4],
+        "server: routes 192.168.0.0/24 added, client routes them through it"
+    );
+    assert_eq!(report[5], "Imported 3 peers");
+    let phone = net.by_name("peer7").unwrap();
+    assert!(phone.private_key.is_none());
+    assert_eq!(phone.id, 7);
+
+    let server = net.by_name("server").unwrap();
+    assert_eq!(server.id, 1);
+    assert_eq!(server.private_key, Some(server_key.into()));
+    assert_eq!(server.endpoint(None), Some("example.com:51820"));
+    assert_eq!(server.listen_port, Some(51820));
+    assert!(server.has_flag("Center"));
+    assert!(server.has_flag("Gateway"));
+    assert!(server.has_flag("Masquerade"));
+    assert_eq!(
+        server.routes(),
+        [IpNetwork::from_str("192.168.0.0/24").unwrap()]
+    );
+
+    let client = net.by_name("client").unwrap();
+    assert_eq!(client.id, 5);
+    assert!(client.has_flag("Keepalive"));
+}
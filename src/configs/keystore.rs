@@ -0,0 +1,229 @@
+// ! Encryption of secrets at rest
+// Secrets are sealed to a keystore X25519 key, whose private part is derived from a passphrase.
+// Sealing needs only the public part, so new peers can be added without the passphrase.
+
+use crate::configs::*;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Environment variable to read keystore passphrase from, instead of asking for it
+pub const PASSPHRASE_VAR: &str = "WG_BOND_PASSPHRASE";
+
+/// Private key or preshared key, as stored in the config file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Secret {
+    /// Base64 of the key itself
+    Plain(String),
+    /// Key encrypted to the keystore: base64 of ephemeral public key followed by ciphertext
+    Sealed { sealed: String },
+}
+
+impl Secret {
+    /// Returns the key, if it's not encrypted
+    pub fn expose(&self) -> Result<&str, String> {
+        match self {
+            Secret::Plain(key) => Ok(key),
+            Secret::Sealed { .. } => Err("Secret is encrypted, keystore has to be unlocked".into()),
+        }
+    }
+
+    fn is_sealed(&self) -> bool {
+        matches!(self, Secret::Sealed { .. })
+    }
+}
+
+impl From<String> for Secret {
+    fn from(key: String) -> Secret {
+        Secret::Plain(key)
+    }
+}
+
+/// Key secrets of the network are encrypted with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Keystore {
+    /// Public part of the keystore key, base64
+    pub public_key: String,
+    /// Salt for passphrase derivation, base64
+    pub salt: String,
+}
+
+fn derive_secret(passphrase: &str, salt: &[u8]) -> Result<StaticSecret, String> {
+    // Parameters are fixed, so changes of library defaults don't lock anyone out
+    let params = argon2::Params::new(19 * 1024, 2, 1, Some(32)).map_err(|e| e.to_string())?;
+    let mut key = [0u8; 32];
+    argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Cannot derive keystore key, {}", e))?;
+    Ok(StaticSecret::from(key))
+}
+
+fn decode_key(key: &str) -> Result<[u8; 32], String> {
+    let decoded = base64::decode(key).map_err(|_| "Cannot decode base64")?;
+    decoded
+        .try_into()
+        .map_err(|_| "Expected key size of 32".to_string())
+}
+
+/// Symmetric key for a sealed secret
+fn cipher(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> ChaCha20Poly1305 {
+    let mut salt = ephemeral.to_vec();
+    salt.extend_from_slice(recipient);
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"wg-bond keystore", &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    ChaCha20Poly1305::new(&key.into())
+}
+
+impl Keystore {
+    /// Creates a keystore unlocked by given passphrase
+    pub fn new(passphrase: &str) -> Result<Keystore, String> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let secret = derive_secret(passphrase, &salt)?;
+        Ok(Keystore {
+            public_key: base64::encode(PublicKey::from(&secret).as_bytes()),
+            salt: base64::encode(salt),
+        })
+    }
+
+    /// Derives keystore key, checking that passphrase is the right one
+    fn unlock(&self, passphrase: &str) -> Result<StaticSecret, String> {
+        let salt = base64::decode(&self.salt).map_err(|_| "Cannot decode keystore salt")?;
+        let secret = derive_secret(passphrase, &salt)?;
+        if PublicKey::from(&secret).as_bytes() != &decode_key(&self.public_key)? {
+            Err("Wrong passphrase")?;
+        }
+        Ok(secret)
+    }
+
+    fn seal(&self, secret: &Secret) -> Result<Secret, String> {
+        let key = match secret {
+            Secret::Plain(key) => key,
+            sealed => return Ok(sealed.clone()),
+        };
+        let recipient = decode_key(&self.public_key)?;
+        let ephemeral = StaticSecret::new(OsRng);
+        let ephemeral_public = *PublicKey::from(&ephemeral).as_bytes();
+        let shared = ephemeral.diffie_hellman(&PublicKey::from(recipient));
+
+        // Every secret gets its own ephemeral key, so a constant nonce is fine
+        let ciphertext = cipher(shared.as_bytes(), &ephemeral_public, &recipient)
+            .encrypt(&Default::default(), key.as_bytes())
+            .map_err(|_| "Cannot encrypt secret")?;
+
+        let mut sealed = ephemeral_public.to_vec();
+        sealed.extend(ciphertext);
+        Ok(Secret::Sealed {
+            sealed: base64::encode(sealed),
+        })
+    }
+
+    fn open(&self, keystore_secret: &StaticSecret, secret: &Secret) -> Result<Secret, String> {
+        let sealed = match secret {
+            Secret::Sealed { sealed } => {
+                base64::decode(sealed).map_err(|_| "Cannot decode base64")?
+            }
+            plain => return Ok(plain.clone()),
+        };
+        if sealed.len() < 32 {
+            Err("Encrypted secret is too short")?;
+        }
+        let (ephemeral_public, ciphertext) = sealed.split_at(32);
+        let ephemeral_public: [u8; 32] = ephemeral_public.try_into().unwrap();
+        let shared = keystore_secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+
+        let key = cipher(
+            shared.as_bytes(),
+            &ephemeral_public,
+            &decode_key(&self.public_key)?,
+        )
+        .decrypt(&Default::default(), ciphertext)
+        .map_err(|_| "Cannot decrypt secret, it's damaged")?;
+        Ok(Secret::Plain(
+            String::from_utf8(key).map_err(|_| "Decrypted secret is not a text")?,
+        ))
+    }
+}
+
+impl WireguardNetworkInfo {
+    fn secrets_mut(&mut self) -> impl Iterator<Item = &mut Secret> {
+        self.peers
+            .iter_mut()
+            .filter_map(|peer| peer.private_key.as_mut())
+            .chain(self.preshared_keys.iter_mut().map(|psk| &mut psk.key))
+    }
+
+    /// Whether some of the secrets are encrypted
+    pub fn is_sealed(&self) -> bool {
+        self.peers
+            .iter()
+            .any(|peer| peer.private_key.as_ref().is_some_and(Secret::is_sealed))
+            || self.preshared_keys.iter().any(|psk| psk.key.is_sealed())
+    }
+
+    /// Encrypts plain secrets, if keystore is enabled
+    pub fn seal_secrets(&mut self) -> Result<(), String> {
+        if let Some(keystore) = self.keystore.clone() {
+            for secret in self.secrets_mut() {
+                *secret = keystore.seal(secret)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypts all secrets in place. They get encrypted again on save, unless keystore is removed.
+    pub fn unseal_secrets(&mut self, passphrase: &str) -> Result<(), String> {
+        if !self.is_sealed() {
+            return Ok(());
+        }
+        let keystore = self
+            .keystore
+            .clone()
+            .ok_or("Secrets are encrypted, but there's no keystore in the config")?;
+        let keystore_secret = keystore.unlock(passphrase)?;
+        for secret in self.secrets_mut() {
+            *secret = keystore.open(&keystore_secret, secret)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces encrypted secrets with empty ones, for exports which only refer to key files
+    pub fn redact_secrets(&mut self) {
+        for secret in self.secrets_mut().filter(|secret| secret.is_sealed()) {
+            *secret = Secret::Plain(String::new());
+        }
+    }
+}
+
+#[test]
+fn test_keystore() {
+    let mut net = WireguardNetworkInfo::new("test", vec![]);
+    net.flags = vec![NetworkFlag::PresharedKeys];
+    net.peers = vec![test_peer("a", 1), test_peer("b", 2)];
+    net.update_preshared_keys();
+    let plain = net.clone();
+
+    net.keystore = Some(Keystore::new("correct horse").unwrap());
+    net.seal_secrets().unwrap();
+    assert!(net.is_sealed());
+    let serialized = serde_json::to_string(&net).unwrap();
+    for peer in plain.peers.iter() {
+        let private_key = peer.private_key.as_ref().unwrap();
+        assert!(!serialized.contains(private_key.expose().unwrap()));
+    }
+    assert!(!serialized.contains(plain.preshared_keys[0].key.expose().unwrap()));
+
+    assert_eq!(
+        net.clone().unseal_secrets("battery staple"),
+        Err("Wrong passphrase".into())
+    );
+    net.unseal_secrets("correct horse").unwrap();
+    assert_eq!(net.peers[0].private_key, plain.peers[0].private_key);
+    assert_eq!(net.preshared_keys[0].key, plain.preshared_keys[0].key);
+}
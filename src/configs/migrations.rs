@@ -0,0 +1,191 @@
+// ! Migrations of config files between schema versions
+// Schema changes which fields with serde defaults can't express bump CURRENT_VERSION,
+// and get a migration from the previous version.
+// Files without a version field were written by wg-bond 0.3.0 or earlier, and have version 0.
+
+use crate::wg_tools;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+
+/// Schema version of config files written by this wg-bond
+pub const CURRENT_VERSION: u32 = 2;
+
+struct Migration {
+    /// Version this migration upgrades to, from the previous one
+    to: u32,
+    description: &'static str,
+    apply: fn(&mut Value) -> Result<(), String>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 1,
+        description: "store public keys of peers, so they are available with encrypted keystore",
+        apply: add_public_keys,
+    },
+    Migration {
+        to: 2,
+        description: "allow multiple named endpoints, separate listen ports from them",
+        apply: name_endpoints,
+    },
+];
+
+fn add_public_keys(config: &mut Value) -> Result<(), String> {
+    let peers = config["peers"]
+        .as_array_mut()
+        .ok_or("peers should be a list")?;
+    for peer in peers.iter_mut() {
+        let private_key = peer["private_key"]
+            .as_str()
+            .ok_or("private key of a peer should be a string")?;
+        let public_key = wg_tools::gen_public_key(private_key)
+            .map_err(|e| format!("bad private key of {}, {}", peer["name"], e))?;
+        peer["public_key"] = json!(public_key);
+    }
+    Ok(())
+}
+
+fn name_endpoints(config: &mut Value) -> Result<(), String> {
+    let peers = config["peers"]
+        .as_array_mut()
+        .ok_or("peers should be a list")?;
+    for peer in peers.iter_mut() {
+        let peer = peer.as_object_mut().ok_or("peer should be an object")?;
+        let (endpoints, port) = match peer.remove("endpoint") {
+            // Peers used to listen on port of their endpoint
+            Some(Value::String(address)) => (
+                json!([{ "name": "default", "address": address }]),
+                Some(super::get_port(address)?),
+            ),
+            _ => (json!([]), None),
+        };
+        peer.insert("endpoints".into(), endpoints);
+        peer.insert("listen_port".into(), json!(port));
+    }
+    Ok(())
+}
+
+/// Returns schema version of a config file
+pub fn version_of(config: &Value) -> Result<u32, String> {
+    match config.get("version") {
+        None => Ok(0),
+        Some(version) => {
+            let version = version
+                .as_u64()
+                .ok_or_else(|| format!("Weird schema version {}", version))?;
+            u32::try_from(version).map_err(|_| too_new(version))
+        }
+    }
+}
+
+fn too_new(version: impl std::fmt::Display) -> String {
+    format!(
+        "Config file has schema version {}, but this wg-bond supports only up to {}. Please upgrade wg-bond.",
+        version, CURRENT_VERSION
+    )
+}
+
+/// Upgrades a config file to the current schema version.
+/// Returns descriptions of applied migrations.
+pub fn migrate(config: &mut Value) -> Result<Vec<String>, String> {
+    let version = version_of(config)?;
+    if version > CURRENT_VERSION {
+        Err(too_new(version))?;
+    }
+
+    let mut applied = vec![];
+    for migration in MIGRATIONS.iter().filter(|m| m.to > version) {
+        (migration.apply)(config).map_err(|e| {
+            format!(
+                "Cannot migrate config to schema version {}, {}",
+                migration.to, e
+            )
+        })?;
+        config["version"] = json!(migration.to);
+        applied.push(format!(
+            "{} -> {}: {}",
+            migration.to - 1,
+            migration.to,
+            migration.description
+        ));
+    }
+    Ok(applied)
+}
+
+#[test]
+fn test_migrate_0_3_0() {
+    let mut config: Value = serde_json::from_str(
+        r#"{
+            "name": "test",
+            "flags": [],
+            "networks": ["10.0.0.0/24"],
+            "peers": [{
+                "name": "gateway",
+                "private_key": "eKVXLS+mrHbH7cFRjllHG/7SqtUeTGLT+uJ59kSHmXw=",
+                "id": 1,
+                "flags": [{"Gateway": {"ignore_local_networks": true}}, "NixOpsMachine"],
+                "endpoint": "example.com:51820"
+            }]
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        migrate(&mut config).unwrap().len(),
+        CURRENT_VERSION as usize
+    );
+    assert_eq!(version_of(&config), Ok(CURRENT_VERSION));
+    let net = serde_json::from_value::<super::WireguardNetworkInfo>(config).unwrap();
+    assert_eq!(net.peers[0].endpoint(None), Some("example.com:51820"));
+    assert!(net.peers[0].key_history.is_empty());
+    assert!(net.acl.is_empty());
+}
+
+#[test]
+fn test_add_public_keys() {
+    let mut config = json!({
+        "peers": [{
+            "name": "gateway",
+            "private_key": "eKVXLS+mrHbH7cFRjllHG/7SqtUeTGLT+uJ59kSHmXw=",
+        }],
+    });
+    add_public_keys(&mut config).unwrap();
+    assert_eq!(
+        config["peers"][0]["public_key"],
+        json!("7rJUnEnV0lg8ZdpMF+idVlxFVq9sbBKZVzRI3zj4BQo=")
+    );
+
+    let mut config = json!({ "peers": [{ "name": "broken", "private_key": "nope" }] });
+    assert!(add_public_keys(&mut config).is_err());
+}
+
+#[test]
+fn test_name_endpoints() {
+    let mut config = json!({
+        "peers": [
+            { "name": "laptop", "endpoint": null },
+            { "name": "server", "endpoint": "example.com:51820" },
+        ],
+    });
+    name_endpoints(&mut config).unwrap();
+    assert_eq!(
+        config["peers"],
+        json!([
+            { "name": "laptop", "endpoints": [], "listen_port": null },
+            {
+                "name": "server",
+                "endpoints": [{ "name": "default", "address": "example.com:51820" }],
+                "listen_port": 51820,
+            },
+        ])
+    );
+}
+
+#[test]
+fn test_refuse_newer() {
+    let mut config = json!({ "version": CURRENT_VERSION + 1 });
+    assert!(migrate(&mut config).is_err());
+    // Would be 0 if truncated
+    let mut config = json!({ "version": 1u64 << 32 });
+    assert_eq!(migrate(&mut config), Err(too_new(1u64 << 32)));
+}
@@ -8,47 +8,50 @@ use std::str::FromStr;
 use strum_macros::AsRefStr;
 use url::Host;
 
+pub mod acl;
+pub mod cidr;
 pub mod conf;
+pub mod drift;
+pub mod failover;
+pub mod firewall;
 pub mod hosts;
+pub mod import;
+pub mod keystore;
+pub mod migrations;
+pub mod networkd;
+pub mod networkmanager;
 pub mod nix;
 pub mod nixops;
+pub mod openwrt;
 pub mod qr;
+pub mod routeros;
 
-const GLOBAL_NET_V4: &[&str; 30] = &[
-    "0.0.0.0/5",
-    "8.0.0.0/7",
-    "11.0.0.0/8",
-    "12.0.0.0/6",
-    "16.0.0.0/4",
-    "32.0.0.0/3",
-    "64.0.0.0/2",
-    "128.0.0.0/3",
-    "160.0.0.0/5",
-    "168.0.0.0/6",
-    "172.0.0.0/12",
-    "172.32.0.0/11",
-    "172.64.0.0/10",
-    "172.128.0.0/9",
-    "173.0.0.0/8",
-    "174.0.0.0/7",
-    "176.0.0.0/4",
-    "192.0.0.0/9",
-    "192.128.0.0/11",
-    "192.160.0.0/13",
-    "192.169.0.0/16",
-    "192.170.0.0/15",
-    "192.172.0.0/14",
-    "192.176.0.0/12",
-    "192.192.0.0/10",
-    "193.0.0.0/8",
-    "194.0.0.0/7",
-    "196.0.0.0/6",
-    "200.0.0.0/5",
-    "208.0.0.0/4",
+pub use acl::AclRule;
+pub use firewall::Firewall;
+pub use keystore::{Keystore, Secret};
+
+/// IPv4 ranges excluded from gateway routes when local networks are ignored
+const LOCAL_NET_V4: &[&str] = &[
+    "0.0.0.0/8",      // "this" network
+    "10.0.0.0/8",     // RFC 1918
+    "127.0.0.0/8",    // loopback
+    "172.16.0.0/12",  // RFC 1918
+    "192.168.0.0/16", // RFC 1918
+    "100.64.0.0/10",  // CGNAT
+    "169.254.0.0/16", // link-local
+    "224.0.0.0/4",    // multicast
+    "240.0.0.0/4",    // reserved and broadcast
 ];
 
-// not yet sure :/
-const GLOBAL_NET_V6: &[&str; 1] = &["::/0"];
+/// IPv6 ranges kept off gateways the same way
+const LOCAL_NET_V6: &[&str] = &[
+    "::/128",        // unspecified
+    "::1/128",       // loopback
+    "::ffff:0:0/96", // IPv4-mapped
+    "fc00::/7",      // ULA
+    "fe80::/10",     // link-local
+    "ff00::/8",      // multicast
+];
 
 /// Checks if endpoint is a valid ip or domain, and extracts port from it.
 /// ```
@@ -129,11 +132,72 @@ pub struct Interface {
     pub post_up: Option<String>,
     pub pre_down: Option<String>,
     pub post_down: Option<String>,
+    /// NAT to set up on this host
+    pub masquerade: Vec<Masquerade>,
+    /// Networks behind this host, traffic from the network is forwarded to
+    pub forwarding: Vec<IpNetwork>,
+    /// Tool masquerade and forwarding rules are set up with
+    pub firewall: Firewall,
+    /// Traffic between peers this host forwards, the rest is dropped. Nothing is filtered if not set.
+    pub acl: Option<Vec<firewall::ForwardRule>>,
+}
+
+// Source NAT of traffic leaving through some interface
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Masquerade {
+    /// Interface traffic leaves through
+    pub interface: String,
+    /// Networks traffic originates from
+    pub sources: Vec<IpNetwork>,
+}
+
+impl Interface {
+    fn join_commands(commands: Vec<String>, hook: &Option<String>) -> Option<String> {
+        let commands = commands
+            .into_iter()
+            .chain(hook.iter().cloned())
+            .collect::<Vec<_>>();
+        if commands.is_empty() {
+            None
+        } else {
+            Some(commands.join(";"))
+        }
+    }
+
+    /// PreUp hook for wg-quick interface `name`, with commands setting up forwarding and masquerade
+    pub fn pre_up_commands(&self, name: &str) -> Option<String> {
+        Self::join_commands(self.firewall.up_commands(name, self), &self.pre_up)
+    }
+
+    /// PreDown hook for wg-quick interface `name`, with commands removing masquerade
+    pub fn pre_down_commands(&self, name: &str) -> Option<String> {
+        Self::join_commands(self.firewall.down_commands(name, self), &self.pre_down)
+    }
+
+    /// Warns about hooks `exporter` can't run, returns comment lines listing them
+    pub fn skip_hooks(&self, exporter: &str) -> String {
+        let mut skipped = String::new();
+        for (hook, value) in [
+            ("PreUp", &self.pre_up),
+            ("PostUp", &self.post_up),
+            ("PreDown", &self.pre_down),
+            ("PostDown", &self.post_down),
+        ] {
+            if let Some(value) = value {
+                warn!("{} doesn't support {} hooks, skipping it", exporter, hook);
+                skipped +=
+                    format!("# {} hook is not supported, skipped: {}\n", hook, value).as_str();
+            }
+        }
+        skipped
+    }
 }
 
 // Mapping of wg-quick peer.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Peer {
+    /// Name of a peer in the network, used for comments and secret file names
+    pub name: String,
     pub public_key: String,
     pub preshared_key: Option<String>,
     pub allowed_ips: Vec<IpNetwork>,
@@ -146,23 +210,60 @@ pub struct ProxyConfig {
     pub networks: Vec<IpNetwork>,
     /// Whether to proxy whole internet, except [local networks](https://en.wikipedia.org/wiki/Private_network)
     /// Useful on mobile devices. Can be redundant.
-    /// See also [`GLOBAL_NET_V4`] and [`GLOBAL_NET_V6`]
+    /// See also [`WireguardNetworkInfo::global_networks`]
     pub use_global_networks: bool,
-    /// Whether to allow to connect to whole internet
+    /// Whether to allow to connect to whole internet.
+    /// Takes precedence over `use_global_networks`.
     pub proxy_internet: bool,
 }
 
 // Describes emergent features of peers, not set by one flag.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Serialize, Deserialize, Debug, AsRefStr, Clone)]
 pub enum PeerFlag {
-    Masquerade { interface: String },
-    Gateway { ignore_local_networks: bool },
-    UseGateway { peer: u128, proxy: ProxyConfig },
-    Segment { mask: u128 },
-    Keepalive { keepalive: u16 },
-    DNS { addresses: Vec<IpAddr> },
+    Masquerade {
+        interface: String,
+    },
+    Gateway {
+        ignore_local_networks: bool,
+        /// Networks to exclude from routes, in addition to local ones
+        #[serde(default)]
+        exclude: Vec<IpNetwork>,
+    },
+    UseGateway {
+        peer: u128,
+        proxy: ProxyConfig,
+    },
+    Segment {
+        mask: u128,
+    },
+    Keepalive {
+        keepalive: u16,
+    },
+    DNS {
+        addresses: Vec<IpAddr>,
+    },
     NixOpsMachine,
     Center,
+    /// Which of named endpoints this peer uses to connect to other peers
+    EndpointProfile {
+        profile: String,
+    },
+    /// Networks behind this peer, routed to it by other peers
+    Routes {
+        networks: Vec<IpNetwork>,
+        /// Interface to masquerade traffic from the network through, when routed networks don't route back
+        #[serde(default)]
+        masquerade: Option<String>,
+    },
+    /// Tool this peer sets up its firewall rules with
+    Firewall {
+        backend: Firewall,
+    },
+    /// Groups access control rules refer to this peer by
+    Groups {
+        groups: Vec<String>,
+    },
 }
 
 #[test]
@@ -177,101 +278,69 @@ impl PeerFlag {
     fn apply_to_interface(&self, network: &WireguardNetworkInfo, interface: &mut Interface) {
         match self {
             PeerFlag::Masquerade { interface: if_name } => {
-                interface.pre_up = network
-                    .networks
-                    .iter()
-                    .map(|f| match f {
-                        IpNetwork::V4(n) => format!(
-                            "iptables {} POSTROUTING -t nat -j MASQUERADE -s {} -o {}",
-                            "-A", &n, if_name
-                        ),
-                        IpNetwork::V6(n) => format!(
-                            "ip6tables {} POSTROUTING -t nat -j MASQUERADE -s {} -o {}",
-                            "-A", &n, if_name
-                        ),
-                    })
-                    .collect::<Vec<_>>()
-                    .join(";")
-                    .into();
-
-                interface.pre_down = network
-                    .networks
-                    .iter()
-                    .map(|f| match f {
-                        IpNetwork::V4(n) => format!(
-                            "iptables {} POSTROUTING -t nat -j MASQUERADE -s {} -o {}",
-                            "-D", &n, if_name
-                        ),
-                        IpNetwork::V6(n) => format!(
-                            "ip6tables {} POSTROUTING -t nat -j MASQUERADE -s {} -o {}",
-                            "-D", &n, if_name
-                        ),
-                    })
-                    .collect::<Vec<_>>()
-                    .join(";")
-                    .into();
+                interface.masquerade.push(Masquerade {
+                    interface: if_name.clone(),
+                    sources: network.networks.clone(),
+                });
+            }
+            PeerFlag::Routes {
+                networks,
+                masquerade,
+            } => {
+                interface.forwarding = networks.clone();
+                if let Some(if_name) = masquerade {
+                    interface.masquerade.push(Masquerade {
+                        interface: if_name.clone(),
+                        sources: network.networks.clone(),
+                    });
+                }
             }
             PeerFlag::DNS { addresses } => {
                 interface.dns = addresses.clone();
             }
+            PeerFlag::Firewall { backend } => {
+                interface.firewall = *backend;
+            }
             _ => {}
         }
     }
 
-    fn apply_to_peer(&self, network: &WireguardNetworkInfo, peer: &mut Peer) {
-        match &self {
-            &PeerFlag::UseGateway { proxy, peer } => {
-                let target_peer = network.by_id(*peer).unwrap();
-            }
-            &PeerFlag::Gateway {
+    fn apply_to_peer(
+        &self,
+        network: &WireguardNetworkInfo,
+        viewer: &PeerInfo,
+        info: &PeerInfo,
+        peer: &mut Peer,
+    ) {
+        match self {
+            PeerFlag::Gateway {
                 ignore_local_networks,
+                ..
             } => {
-                let has_ipv4 = network.networks.iter().any(IpNetwork::is_ipv4);
-                let has_ipv6 = network.networks.iter().any(IpNetwork::is_ipv6);
-
                 if *ignore_local_networks {
-                    let e: &[&str] = &[];
-                    peer.allowed_ips.append(
-                        &mut empty()
-                            .chain(if has_ipv4 {
-                                GLOBAL_NET_V4.iter()
-                            } else {
-                                e.iter()
-                            })
-                            .chain(if has_ipv6 {
-                                GLOBAL_NET_V6.iter()
-                            } else {
-                                e.iter()
-                            })
-                            .map(|a| IpNetwork::from_str(a).unwrap())
-                            .collect(),
-                    )
+                    peer.allowed_ips.append(&mut network.global_networks(info))
                 } else {
-                    if has_ipv4 {
-                        peer.allowed_ips
-                            .insert(0, IpNetwork::from_str("0.0.0.0/0").unwrap())
-                    }
-
-                    if has_ipv6 {
-                        peer.allowed_ips
-                            .insert(0, IpNetwork::from_str("0::/0").unwrap())
+                    for default_route in network.default_routes() {
+                        peer.allowed_ips.insert(0, default_route)
                     }
                 }
             }
-            &PeerFlag::Center => {
+            // Only one peer can have a route, other hubs and hubs themselves get just addresses
+            PeerFlag::Center if network.hubs(viewer).first().map(|hub| hub.id) == Some(info.id) => {
                 for network in network.networks.iter().rev() {
                     peer.allowed_ips.insert(0, *network)
                 }
             }
+            PeerFlag::Routes { networks, .. } => peer.allowed_ips.extend(networks.iter().cloned()),
             _ => {}
         }
     }
 
     fn apply_to_configuration(
         &self,
-        _network: &WireguardNetworkInfo,
+        network: &WireguardNetworkInfo,
         config: &mut WireguardConfiguration,
-    ) {
+    ) -> Result<(), String> {
         match self {
             PeerFlag::Keepalive { keepalive } => {
                 for peer in config.peers.iter_mut() {
@@ -280,8 +349,26 @@ impl PeerFlag {
                     }
                 }
             }
+            PeerFlag::UseGateway { peer, proxy } => {
+                let gateway = network.gateway(*peer)?;
+                let proxied = network.proxied_networks(gateway, proxy)?;
+                // Entries of keys in grace without routes stay without them
+                for peer in config
+                    .peers
+                    .iter_mut()
+                    .filter(|p| p.name == gateway.name && !p.allowed_ips.is_empty())
+                {
+                    // Keeps what flags of the gateway itself routed through it, like Center
+                    for net in proxied.iter() {
+                        if !peer.allowed_ips.contains(net) {
+                            peer.allowed_ips.push(*net);
+                        }
+                    }
+                }
+            }
             _ => {}
         }
+        Ok(())
     }
 }
 
@@ -289,22 +376,166 @@ impl PeerFlag {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeerInfo {
     pub name: String,
-    pub private_key: String,
+    /// Unknown for peers which generate their own keys
+    pub private_key: Option<Secret>,
+    pub public_key: String,
     pub id: u128,
     pub flags: Vec<PeerFlag>,
-    pub endpoint: Option<String>,
+    /// Addresses other peers connect to, ports may differ from the listen port behind a port forward
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    /// Port the peer listens on, random if not set
+    #[serde(default)]
+    pub listen_port: Option<u16>,
+    /// Addresses overriding ones computed from id, at most one per network
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+    /// Unix time current key was set at, unknown for keys older than rotation support
+    #[serde(default)]
+    pub key_created: Option<u64>,
+    /// Keys replaced by rotation, oldest first
+    #[serde(default)]
+    pub key_history: Vec<RetiredKey>,
+}
+
+/// Address of a peer, named after a part of the network it's reachable from, like "lan" or "v6"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub name: String,
+    pub address: String,
+}
+
+/// Name of the endpoint used when viewer has no profile, or target has no such endpoint
+pub const DEFAULT_ENDPOINT: &str = "default";
+
+/// Key a peer used before rotation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetiredKey {
+    pub public_key: String,
+    /// Unix time the key was set at, if known
+    pub created: Option<u64>,
+    /// Unix time the key was replaced at
+    pub retired: u64,
+    /// Until this time the key is exported along with the new one
+    pub grace_until: Option<u64>,
+}
+
+/// Current unix time, in seconds
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
 impl PeerInfo {
+    /// Endpoint with given name, falling back to the default one, and then to the first one
+    pub fn endpoint(&self, profile: Option<&str>) -> Option<&str> {
+        let named = |name: &str| self.endpoints.iter().find(|e| e.name == name);
+        profile
+            .and_then(named)
+            .or_else(|| named(DEFAULT_ENDPOINT))
+            .or_else(|| self.endpoints.first())
+            .map(|e| e.address.as_str())
+    }
+
+    /// Adds an endpoint, replacing one with the same name
+    pub fn set_endpoint(&mut self, endpoint: Endpoint) {
+        match self.endpoints.iter_mut().find(|e| e.name == endpoint.name) {
+            Some(existing) => *existing = endpoint,
+            None => self.endpoints.push(endpoint),
+        }
+    }
+
+    /// Endpoint profile this peer uses to connect to others
+    pub fn endpoint_profile(&self) -> Option<&str> {
+        self.flags.iter().find_map(|f| match f {
+            PeerFlag::EndpointProfile { profile } => Some(profile.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Replaces key of the peer, keeping the old one in history.
+    /// Old key stays in exported configs until `grace_until`.
+    pub fn rotate_key(
+        &mut self,
+        private_key: Option<Secret>,
+        public_key: String,
+        grace_until: Option<u64>,
+    ) {
+        let now = unix_time();
+        self.key_history.push(RetiredKey {
+            public_key: std::mem::replace(&mut self.public_key, public_key),
+            created: self.key_created,
+            retired: now,
+            grace_until,
+        });
+        self.private_key = private_key;
+        self.key_created = Some(now);
+    }
+
+    /// Ends grace period of retired keys, so the current one gets the routes
+    pub fn confirm_key(&mut self) {
+        for key in self.key_history.iter_mut() {
+            key.grace_until = None;
+        }
+    }
+
+    /// Retired keys which are still exported at given time
+    pub fn keys_in_grace(&self, now: u64) -> impl Iterator<Item = &RetiredKey> {
+        self.key_history
+            .iter()
+            .filter(move |key| key.grace_until.is_some_and(|until| until > now))
+    }
+
     pub fn has_flag(&self, flag_name: &str) -> bool {
         self.flags.iter().any(|f| f.as_ref() == flag_name)
     }
 
+    /// Networks routed through this peer
+    pub fn routes(&self) -> &[IpNetwork] {
+        self.flags
+            .iter()
+            .find_map(|f| match f {
+                PeerFlag::Routes { networks, .. } => Some(networks.as_slice()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Bitmask of segments this peer belongs to.
+    /// Peers without a Segment flag belong to every segment.
+    pub fn segment_mask(&self) -> u128 {
+        self.flags
+            .iter()
+            .find_map(|f| match f {
+                PeerFlag::Segment { mask } => Some(*mask),
+                _ => None,
+            })
+            .unwrap_or(!0)
+    }
+
+    /// Whether two peers should know about each other.
+    /// Centers see everyone, other peers have to share a segment.
+    pub fn sees(&self, other: &PeerInfo) -> bool {
+        self.has_flag("Center")
+            || other.has_flag("Center")
+            || self.segment_mask() & other.segment_mask() != 0
+    }
+
     pub fn derive_interface(&self) -> Result<Interface, String> {
         Ok(Interface {
             address: vec![],
-            private_key: self.private_key.clone(),
-            port: self.endpoint.clone().map(|f| get_port(f)).transpose()?,
+            private_key: self
+                .private_key
+                .as_ref()
+                .ok_or(format!(
+                    "Private key of {} is unknown, only its public key is stored",
+                    self.name
+                ))?
+                .expose()?
+                .to_string(),
+            port: self.listen_port,
             dns: vec![],
             fw_mark: None,
             table: None,
@@ -312,14 +543,19 @@ impl PeerInfo {
             post_up: None,
             pre_down: None,
             post_down: None,
+            masquerade: vec![],
+            forwarding: vec![],
+            firewall: Firewall::Iptables,
+            acl: None,
         })
     }
 
     pub fn derive_peer(&self) -> Result<Peer, String> {
         Ok(Peer {
-            public_key: wg_tools::gen_public_key(&self.private_key)?,
+            name: self.name.clone(),
+            public_key: self.public_key.clone(),
             allowed_ips: vec![],
-            endpoint: self.endpoint.clone(),
+            endpoint: self.endpoint(None).map(String::from),
             persistent_keepalive: None,
             preshared_key: None,
         })
@@ -329,16 +565,49 @@ impl PeerInfo {
 // Overall network informatiom
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WireguardNetworkInfo {
+    /// Schema version, see [`migrations`]
+    pub version: u32,
     pub name: String,
     pub flags: Vec<NetworkFlag>,
     pub networks: Vec<IpNetwork>,
     pub peers: Vec<PeerInfo>,
+    /// Keys used by [`NetworkFlag::PresharedKeys`]
+    #[serde(default)]
+    pub preshared_keys: Vec<PresharedKey>,
+    /// Secrets are encrypted with keystore key if it's set
+    pub keystore: Option<Keystore>,
+    /// Traffic Center peers forward between peers, see [`acl`]
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
 }
 
 #[derive(Serialize, Deserialize, Debug, AsRefStr, Clone)]
 pub enum NetworkFlag {
     Centralized,
-    // TODO: Add symmetric keys overlay
+    /// Every pair of peers gets its own symmetric key on top of the usual keypairs
+    PresharedKeys,
+    /// Center every peer routes the network through, instead of the one with the lowest id.
+    /// It's the same for all peers, as peers only accept traffic of other peers from the hub
+    /// they route the network through.
+    PrimaryHub {
+        peer: u128,
+    },
+}
+
+// Preshared key of a pair of peers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PresharedKey {
+    /// Ids of peers sharing this key, lower one first
+    pub peers: (u128, u128),
+    pub key: Secret,
+}
+
+fn peer_pair(a: u128, b: u128) -> (u128, u128) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
 }
 
 /// Searches for an item matching given pattern
@@ -352,16 +621,225 @@ macro_rules! find_pattern {
 }
 
 impl WireguardNetworkInfo {
-    pub fn map_to_peer(&self, info: &PeerInfo) -> Result<Peer, String> {
-        let mut peer = info.derive_peer()?;
-        peer.allowed_ips = self
+    pub fn new(name: &str, networks: Vec<IpNetwork>) -> WireguardNetworkInfo {
+        WireguardNetworkInfo {
+            version: migrations::CURRENT_VERSION,
+            name: name.to_string(),
+            flags: vec![],
+            networks,
+            peers: vec![],
+            preshared_keys: vec![],
+            keystore: None,
+            acl: vec![],
+        }
+    }
+
+    /// Address of a peer in a network, static one if set, or computed from id
+    pub fn peer_address(&self, info: &PeerInfo, net: IpNetwork) -> Result<IpAddr, String> {
+        match info
+            .addresses
+            .iter()
+            .find(|address| net.contains(**address))
+        {
+            Some(address) => Ok(*address),
+            None => get_network_address(net, info.id)
+                .map_err(|e| format!("Cannot compute address of {}, {}", info.name, e)),
+        }
+    }
+
+    /// Addresses of a peer in every network
+    pub fn peer_addresses(&self, info: &PeerInfo) -> Result<Vec<IpAddr>, String> {
+        self.networks
+            .iter()
+            .map(|net| self.peer_address(info, *net))
+            .collect()
+    }
+
+    /// Addresses of a peer in every network, as single-address networks
+    pub fn peer_networks(&self, info: &PeerInfo) -> Result<Vec<IpNetwork>, String> {
+        Ok(self
+            .peer_addresses(info)?
+            .into_iter()
+            .map(IpNetwork::from)
+            .collect::<Vec<_>>())
+    }
+
+    /// Checks that every peer has an address in every network, and no addresses are shared
+    pub fn check_addresses(&self) -> Result<(), String> {
+        for peer in self.peers.iter() {
+            self.check_address_collisions(peer)?;
+        }
+        Ok(())
+    }
+
+    /// Largest id which has an address in every network
+    pub fn max_id(&self) -> u128 {
+        self.networks
+            .iter()
+            .map(|net| max_host_id(*net))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Ids new peers can't get: ones used by peers, and ones computing into static addresses
+    fn taken_ids(&self) -> Vec<u128> {
+        let mut taken = self.peers.iter().map(|p| p.id).collect::<Vec<_>>();
+        for address in self.peers.iter().flat_map(|p| p.addresses.iter()) {
+            taken.extend(
+                self.networks
+                    .iter()
+                    .filter_map(|net| get_address_id(*net, *address)),
+            )
+        }
+        taken.sort_unstable();
+        taken.dedup();
+        taken
+    }
+
+    /// Lowest id free in all networks
+    pub fn free_id(&self) -> Result<u128, String> {
+        let taken = self.taken_ids();
+        (1..=self.max_id())
+            .find(|id| !taken.contains(id))
+            .ok_or_else(|| format!("Network is full, all {} addresses are taken", self.max_id()))
+    }
+
+    /// Number of peers which can still be added
+    pub fn capacity(&self) -> u128 {
+        let max_id = self.max_id();
+        let taken = self
+            .taken_ids()
+            .into_iter()
+            .filter(|id| (1..=max_id).contains(id))
+            .count();
+        max_id - taken as u128
+    }
+
+    /// Checks that an address can be assigned to a peer, returns network it belongs to
+    pub fn check_static_address(&self, address: IpAddr) -> Result<IpNetwork, String> {
+        let net = self
             .networks
             .iter()
-            .map(|f| get_network_address_as_network(*f, info.id))
+            .find(|net| net.contains(address))
+            .ok_or(format!("Address {} is outside of the network", address))?;
+        let id = get_address_id(*net, address).unwrap();
+        if id == 0 || id > max_host_id(*net) {
+            Err(format!(
+                "Address {} is reserved as network or broadcast address",
+                address
+            ))?;
+        }
+        Ok(*net)
+    }
+
+    /// Fails if addresses of a peer are used by any other peer
+    pub fn check_address_collisions(&self, info: &PeerInfo) -> Result<(), String> {
+        let addresses = self.peer_addresses(info)?;
+        for other in self.peers.iter().filter(|p| p.id != info.id) {
+            if let Some(address) = self
+                .peer_addresses(other)?
+                .into_iter()
+                .find(|a| addresses.contains(a))
+            {
+                Err(format!(
+                    "Address {} of {} is already used by {}",
+                    address, info.name, other.name
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if networks routed through a peer overlap with the network, or with routes of other peers
+    pub fn check_routes(&self, info: &PeerInfo) -> Result<(), String> {
+        let overlaps =
+            |a: &IpNetwork, b: &IpNetwork| a.contains(b.network()) || b.contains(a.network());
+        for route in info.routes() {
+            if let Some(network) = self.networks.iter().find(|n| overlaps(n, route)) {
+                Err(format!(
+                    "Route {} of {} overlaps with network {}",
+                    route, info.name, network
+                ))?;
+            }
+            for other in self.peers.iter().filter(|p| p.id != info.id) {
+                if let Some(other_route) = other.routes().iter().find(|r| overlaps(r, route)) {
+                    Err(format!(
+                        "Route {} of {} overlaps with route {} of {}",
+                        route, info.name, other_route, other.name
+                    ))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Default routes for every address family used in the network
+    pub fn default_routes(&self) -> Vec<IpNetwork> {
+        let mut routes = vec![];
+        if self.networks.iter().any(IpNetwork::is_ipv4) {
+            routes.push(IpNetwork::from_str("0.0.0.0/0").unwrap())
+        }
+        if self.networks.iter().any(IpNetwork::is_ipv6) {
+            routes.push(IpNetwork::from_str("0::/0").unwrap())
+        }
+        routes
+    }
+
+    /// Whole internet except local networks, for every address family used in the network.
+    /// Also excludes gateway's own endpoints and networks it was told to exclude.
+    pub fn global_networks(&self, gateway: &PeerInfo) -> Vec<IpNetwork> {
+        let mut exclusions = LOCAL_NET_V4
+            .iter()
+            .chain(LOCAL_NET_V6.iter())
+            .map(|a| IpNetwork::from_str(a).unwrap())
             .collect::<Vec<_>>();
 
+        for endpoint in gateway.endpoints.iter() {
+            match split_endpoint(endpoint.address.clone()) {
+                Ok((Host::Ipv4(ip), _)) => {
+                    exclusions.push(IpNetwork::new(IpAddr::V4(ip), 32).unwrap())
+                }
+                Ok((Host::Ipv6(ip), _)) => {
+                    exclusions.push(IpNetwork::new(IpAddr::V6(ip), 128).unwrap())
+                }
+                _ => {}
+            }
+        }
+
+        for flag in &gateway.flags {
+            if let PeerFlag::Gateway { exclude, .. } = flag {
+                exclusions.extend(exclude.iter().cloned())
+            }
+        }
+
+        cidr::exclude(&self.default_routes(), &exclusions)
+    }
+
+    /// Networks a client should route through a gateway, according to its [`ProxyConfig`]
+    pub fn proxied_networks(
+        &self,
+        gateway: &PeerInfo,
+        proxy: &ProxyConfig,
+    ) -> Result<Vec<IpNetwork>, String> {
+        let mut networks = self.peer_networks(gateway)?;
+        networks.extend(gateway.routes().iter().cloned());
+        networks.extend(proxy.networks.iter().cloned());
+        if proxy.proxy_internet {
+            networks.append(&mut self.default_routes());
+        } else if proxy.use_global_networks {
+            networks.append(&mut self.global_networks(gateway));
+        }
+        Ok(networks)
+    }
+
+    /// Peer entry of `info` in config of `viewer`
+    pub fn map_to_peer(&self, viewer: &PeerInfo, info: &PeerInfo) -> Result<Peer, String> {
+        let mut peer = info.derive_peer()?;
+        peer.endpoint = info.endpoint(viewer.endpoint_profile()).map(String::from);
+        peer.allowed_ips = self.peer_networks(info)?;
+
         for flag in &info.flags {
-            flag.apply_to_peer(self, &mut peer)
+            flag.apply_to_peer(self, viewer, info, &mut peer)
         }
         Ok(peer)
     }
@@ -369,24 +847,31 @@ impl WireguardNetworkInfo {
     pub fn map_to_interface(&self, info: &PeerInfo) -> Result<Interface, String> {
         let mut interface = info.derive_interface()?;
 
-        interface.address = self
-            .networks
-            .iter()
-            .map(|f| get_network_address(*f, info.id))
-            .collect::<Vec<_>>();
+        interface.address = self.peer_addresses(info)?;
 
         for flag in &info.flags {
             flag.apply_to_interface(self, &mut interface)
         }
+        if info.has_flag("Center") {
+            interface.acl = self.forward_rules()?;
+        }
         Ok(interface)
     }
 
+    /// Gateway a UseGateway flag points to
+    fn gateway(&self, id: u128) -> Result<&PeerInfo, String> {
+        self.by_id(id).ok_or(format!(
+            "UseGateway flag points to nonexistent peer #{}",
+            id
+        ))
+    }
+
     /// Returns a list of peers for configuration of a given peer
-    pub fn peer_list(&self, info: &PeerInfo) -> Vec<&PeerInfo> {
+    pub fn peer_list(&self, info: &PeerInfo) -> Result<Vec<&PeerInfo>, String> {
         let others = || {
             self.peers
                 .iter()
-                .filter(|peer| peer.id != info.id)
+                .filter(|peer| peer.id != info.id && info.sees(peer))
                 .collect::<Vec<_>>()
         };
 
@@ -394,17 +879,10 @@ impl WireguardNetworkInfo {
             find_pattern!(info.flags => PeerFlag::UseGateway { .. })
         {
             // in this case we only need a gateway
-            let gateway = self.by_id(peer).expect(
-                format!(
-                    "UseGateway flag on #{} points to nonexistent peer #{}!",
-                    info.id, peer
-                )
-                .as_str(),
-            );
-            return vec![gateway];
+            return Ok(vec![self.gateway(peer)?]);
         }
 
-        if self.has_flag("Centralized") {
+        Ok(if self.has_flag("Centralized") {
             if info.has_flag("Center") {
                 others()
             } else {
@@ -415,6 +893,73 @@ impl WireguardNetworkInfo {
             }
         } else {
             others()
+        })
+    }
+
+    /// Id of the Center set by PrimaryHub flag
+    pub fn primary_hub(&self) -> Option<u128> {
+        self.flags.iter().find_map(|f| match f {
+            NetworkFlag::PrimaryHub { peer } => Some(*peer),
+            _ => None,
+        })
+    }
+
+    /// Center peers a peer routes the network through, primary one first.
+    /// Primary one is set by PrimaryHub flag, or is the one with the lowest id. Centers have no hubs.
+    pub fn hubs(&self, info: &PeerInfo) -> Vec<&PeerInfo> {
+        if info.has_flag("Center") {
+            return vec![];
+        }
+        let mut hubs = self
+            .peers
+            .iter()
+            .filter(|peer| peer.has_flag("Center"))
+            .collect::<Vec<_>>();
+        hubs.sort_by_key(|hub| hub.id);
+        if let Some(primary) = self
+            .primary_hub()
+            .and_then(|id| hubs.iter().position(|hub| hub.id == id))
+        {
+            let hub = hubs.remove(primary);
+            hubs.insert(0, hub);
+        }
+        hubs
+    }
+
+    /// Returns key shared by two peers, if preshared keys are enabled
+    pub fn preshared_key(&self, a: u128, b: u128) -> Option<&Secret> {
+        if !self.has_flag("PresharedKeys") {
+            return None;
+        }
+        let pair = peer_pair(a, b);
+        self.preshared_keys
+            .iter()
+            .find(|psk| psk.peers == pair)
+            .map(|psk| &psk.key)
+    }
+
+    /// Generates missing preshared keys and drops ones of removed peers.
+    /// Existing keys are never changed, so exports stay stable.
+    pub fn update_preshared_keys(&mut self) {
+        if !self.has_flag("PresharedKeys") {
+            self.preshared_keys.clear();
+            return;
+        }
+
+        let ids = self.peers.iter().map(|p| p.id).collect::<Vec<_>>();
+        self.preshared_keys
+            .retain(|psk| ids.contains(&psk.peers.0) && ids.contains(&psk.peers.1));
+
+        for (i, a) in ids.iter().enumerate() {
+            for b in ids.iter().skip(i + 1) {
+                let pair = peer_pair(*a, *b);
+                if !self.preshared_keys.iter().any(|psk| psk.peers == pair) {
+                    self.preshared_keys.push(PresharedKey {
+                        peers: pair,
+                        key: wg_tools::gen_preshared_key().into(),
+                    });
+                }
+            }
         }
     }
 
@@ -422,16 +967,41 @@ impl WireguardNetworkInfo {
         let mut config = WireguardConfiguration {
             interface: self.map_to_interface(info)?,
             peers: self
-                .peer_list(info)
+                .peer_list(info)?
                 .iter()
-                .map(|x| self.map_to_peer(x))
-                .collect::<Result<Vec<_>, _>>()?,
+                .map(|x| {
+                    let mut peer = self.map_to_peer(info, x)?;
+                    peer.preshared_key = self
+                        .preshared_key(info.id, x.id)
+                        .map(|psk| psk.expose().map(String::from))
+                        .transpose()?;
+
+                    // A route goes to one peer only, so the latest old key keeps routes
+                    // until the new one is confirmed, other keys are only accepted
+                    let mut peers = x
+                        .keys_in_grace(unix_time())
+                        .map(|old| Peer {
+                            public_key: old.public_key.clone(),
+                            allowed_ips: vec![],
+                            ..peer.clone()
+                        })
+                        .collect::<Vec<_>>();
+                    if let Some(latest) = peers.last_mut() {
+                        std::mem::swap(&mut latest.allowed_ips, &mut peer.allowed_ips);
+                    }
+                    peers.push(peer);
+                    Ok(peers)
+                })
+                .collect::<Result<Vec<_>, String>>()?
+                .into_iter()
+                .flatten()
+                .collect(),
             name: self.name.clone(),
         };
 
-        info.flags
-            .iter()
-            .for_each(|flag| flag.apply_to_configuration(self, &mut config));
+        for flag in info.flags.iter() {
+            flag.apply_to_configuration(self, &mut config)?;
+        }
 
         Ok(config)
     }
@@ -453,27 +1023,50 @@ impl WireguardNetworkInfo {
     }
 }
 
+/// Largest host id in a network. Network and broadcast addresses are never given to peers.
+pub fn max_host_id(net: IpNetwork) -> u128 {
+    match net {
+        IpNetwork::V4(n) => {
+            let hosts = (!0u32).checked_shr(n.prefix() as u32).unwrap_or(0) as u128;
+            // /31 and /32 have no broadcast address
+            if n.prefix() < 31 {
+                hosts - 1
+            } else {
+                hosts
+            }
+        }
+        IpNetwork::V6(n) => (!0u128).checked_shr(n.prefix() as u32).unwrap_or(0),
+    }
+}
+
 fn get_network_address_v4(net: Ipv4Network, num: u32) -> Ipv4Addr {
-    assert!(net.size() > num);
     Ipv4Addr::from(u32::from_be_bytes(net.ip().octets()) | (num & (!0u32 >> net.prefix())))
 }
 
 fn get_network_address_v6(net: Ipv6Network, num: u128) -> Ipv6Addr {
-    assert!(net.size() > num);
     Ipv6Addr::from(u128::from_be_bytes(net.ip().octets()) | (num & (!0u128 >> net.prefix())))
 }
 
-pub fn get_network_address_as_network(net: IpNetwork, num: u128) -> IpNetwork {
-    match get_network_address(net, num) {
-        a @ IpAddr::V4(_) => IpNetwork::new(a, 32).unwrap(),
-        a @ IpAddr::V6(_) => IpNetwork::new(a, 128).unwrap(),
+pub fn get_network_address(net: IpNetwork, num: u128) -> Result<IpAddr, String> {
+    if num == 0 || num > max_host_id(net) {
+        Err(format!("id {} doesn't fit into network {}", num, net))?;
     }
+    Ok(match net {
+        IpNetwork::V4(n) => IpAddr::V4(get_network_address_v4(n, num as u32)),
+        IpNetwork::V6(n) => IpAddr::V6(get_network_address_v6(n, num)),
+    })
 }
 
-pub fn get_network_address(net: IpNetwork, num: u128) -> IpAddr {
-    match net {
-        IpNetwork::V4(n) => IpAddr::V4(get_network_address_v4(n, num.try_into().unwrap())),
-        IpNetwork::V6(n) => IpAddr::V6(get_network_address_v6(n, num.try_into().unwrap())),
+/// Inverse of [`get_network_address`], returns id of an address in a network
+pub fn get_address_id(net: IpNetwork, address: IpAddr) -> Option<u128> {
+    match (net, address) {
+        (IpNetwork::V4(n), IpAddr::V4(a)) if n.contains(a) => {
+            Some((u32::from(a) & !u32::from(n.mask())) as u128)
+        }
+        (IpNetwork::V6(n), IpAddr::V6(a)) if n.contains(a) => {
+            Some(u128::from(a) & !u128::from(n.mask()))
+        }
+        _ => None,
     }
 }
 
@@ -482,4 +1075,328 @@ pub trait ConfigType {
     // let config = net.get_configuration(my_peer);
     // let interface = net.map_to_interface(my_peer);
     fn write_config(net: WireguardConfiguration, options: Self::ExportConfig) -> String;
+
+    /// Whether exported config contains private and preshared keys, instead of referring to key files
+    fn needs_secrets(_options: &Self::ExportConfig) -> bool {
+        true
+    }
+
+    /// Whether exported config sets up access control rules of Centers
+    fn supports_acl() -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+pub fn test_peer(name: &str, id: u128) -> PeerInfo {
+    let private_key = wg_tools::gen_private_key();
+    PeerInfo {
+        name: name.into(),
+        public_key: wg_tools::gen_public_key(&private_key).unwrap(),
+        private_key: Some(private_key.into()),
+        id,
+        flags: vec![],
+        endpoints: vec![],
+        listen_port: None,
+        addresses: vec![],
+        key_created: None,
+        key_history: vec![],
+    }
+}
+
+#[test]
+fn test_preshared_keys() {
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.flags = vec![NetworkFlag::PresharedKeys];
+    net.peers = vec![test_peer("a", 1), test_peer("b", 2), test_peer("c", 3)];
+    net.update_preshared_keys();
+    assert_eq!(net.preshared_keys.len(), 3);
+
+    let key = net.preshared_key(2, 1).cloned();
+    assert!(key.is_some());
+    net.update_preshared_keys();
+    assert_eq!(net.preshared_key(1, 2).cloned(), key);
+
+    let config = net.get_configuration(&net.peers[0]).unwrap();
+    assert_eq!(config.peers[0].preshared_key.clone().map(Secret::from), key);
+
+    net.peers.remove(2);
+    net.update_preshared_keys();
+    assert_eq!(net.preshared_keys.len(), 1);
+}
+
+#[test]
+fn test_use_gateway() {
+    let mut client = test_peer("client", 2);
+    client.flags.push(PeerFlag::UseGateway {
+        peer: 1,
+        proxy: ProxyConfig {
+            networks: vec![IpNetwork::from_str("192.168.1.0/24").unwrap()],
+            use_global_networks: false,
+            proxy_internet: true,
+        },
+    });
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.peers = vec![test_peer("gateway", 1), client, test_peer("other", 3)];
+
+    let config = net.get_configuration(&net.peers[1]).unwrap();
+    assert_eq!(config.peers.len(), 1);
+    assert_eq!(
+        config.peers[0].allowed_ips,
+        ["10.0.0.1/32", "192.168.1.0/24", "0.0.0.0/0"]
+            .iter()
+            .map(|n| IpNetwork::from_str(n).unwrap())
+            .collect::<Vec<_>>()
+    );
+
+    // Gateway being a Center still routes the whole network
+    net.peers[0].flags.push(PeerFlag::Center);
+    let config = net.get_configuration(&net.peers[1]).unwrap();
+    assert_eq!(
+        config.peers[0].allowed_ips,
+        ["10.0.0.0/24", "10.0.0.1/32", "192.168.1.0/24", "0.0.0.0/0"]
+            .iter()
+            .map(|n| IpNetwork::from_str(n).unwrap())
+            .collect::<Vec<_>>()
+    );
+
+    net.peers.remove(0);
+    assert_eq!(
+        net.get_configuration(&net.peers[0]).err(),
+        Some("UseGateway flag points to nonexistent peer #1".into())
+    );
+}
+
+#[test]
+fn test_segments() {
+    let mut contractor = test_peer("contractor", 1);
+    contractor.flags.push(PeerFlag::Segment { mask: 0b01 });
+    let mut server = test_peer("server", 2);
+    server.flags.push(PeerFlag::Segment { mask: 0b10 });
+    let mut center = test_peer("center", 3);
+    center.flags.push(PeerFlag::Segment { mask: 0b10 });
+    center.flags.push(PeerFlag::Center);
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.peers = vec![contractor, server, center, test_peer("admin", 4)];
+
+    let names = |info: &PeerInfo| {
+        net.peer_list(info)
+            .unwrap()
+            .iter()
+            .map(|p| p.name.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&net.peers[0]), ["center", "admin"]);
+    assert_eq!(names(&net.peers[1]), ["center", "admin"]);
+    assert_eq!(names(&net.peers[2]), ["contractor", "server", "admin"]);
+}
+
+#[test]
+fn test_gateway_exclusions() {
+    let mut gateway = test_peer("gateway", 1);
+    gateway.endpoints = vec![Endpoint {
+        name: DEFAULT_ENDPOINT.into(),
+        address: "1.2.3.4:51820".into(),
+    }];
+    gateway.flags.push(PeerFlag::Gateway {
+        ignore_local_networks: true,
+        exclude: vec![IpNetwork::from_str("2001:db8::/32").unwrap()],
+    });
+    let mut net = WireguardNetworkInfo::new(
+        "test",
+        vec![
+            IpNetwork::from_str("10.0.0.0/24").unwrap(),
+            IpNetwork::from_str("fd00::/64").unwrap(),
+        ],
+    );
+    net.peers = vec![gateway];
+
+    let routes = net.global_networks(&net.peers[0]);
+    let routed = |ip: &str| {
+        let ip = IpAddr::from_str(ip).unwrap();
+        routes.iter().any(|n| n.contains(ip))
+    };
+    assert!(routed("8.8.8.8"));
+    assert!(routed("1.2.3.5"));
+    assert!(!routed("1.2.3.4"));
+    assert!(!routed("192.168.1.1"));
+    assert!(!routed("100.64.0.1"));
+    assert!(!routed("127.0.0.1"));
+    assert!(!routed("0.1.2.3"));
+    assert!(routed("2606:4700::1111"));
+    assert!(!routed("2001:db8::1"));
+    assert!(!routed("fd12::1"));
+    assert!(!routed("fe80::1"));
+    assert!(!routed("::1"));
+    assert!(!routed("::"));
+    assert!(!routed("::ffff:192.168.1.1"));
+    assert!(routed("::2"));
+}
+
+#[test]
+fn test_key_rotation() {
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.peers = vec![test_peer("a", 1), test_peer("b", 2)];
+    let old_key = net.peers[1].public_key.clone();
+
+    let private_key = wg_tools::gen_private_key();
+    let public_key = wg_tools::gen_public_key(&private_key).unwrap();
+    net.peers[1].rotate_key(
+        Some(private_key.into()),
+        public_key.clone(),
+        Some(unix_time() + 3600),
+    );
+    net.peers[1].rotate_key(None, "new".into(), Some(unix_time() - 1));
+
+    let b = &net.peers[1];
+    assert_eq!(b.key_history.len(), 2);
+    assert_eq!(b.key_history[0].public_key, old_key);
+    assert_eq!(b.key_history[1].created, b.key_created);
+
+    let config = net.get_configuration(&net.peers[0]).unwrap();
+    let keys = config
+        .peers
+        .iter()
+        .map(|p| p.public_key.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(keys, [old_key.as_str(), "new"]);
+    assert_eq!(
+        config.peers[0].allowed_ips,
+        [IpNetwork::from_str("10.0.0.2/32").unwrap()]
+    );
+    assert!(config.peers[1].allowed_ips.is_empty());
+
+    net.peers[1].confirm_key();
+    let config = net.get_configuration(&net.peers[0]).unwrap();
+    assert_eq!(config.peers.len(), 1);
+    assert_eq!(
+        config.peers[0].allowed_ips,
+        [IpNetwork::from_str("10.0.0.2/32").unwrap()]
+    );
+}
+
+#[test]
+fn test_static_address() {
+    let mut net = WireguardNetworkInfo::new(
+        "test",
+        vec![
+            IpNetwork::from_str("10.0.0.0/24").unwrap(),
+            IpNetwork::from_str("fd00::/64").unwrap(),
+        ],
+    );
+    let mut server = test_peer("server", 1);
+    let address = IpAddr::from_str("10.0.0.254").unwrap();
+    assert_eq!(net.check_static_address(address), Ok(net.networks[0]));
+    server.addresses = vec![address];
+    net.peers = vec![server, test_peer("client", 2)];
+
+    let interface = net.map_to_interface(&net.peers[0]).unwrap();
+    assert_eq!(
+        interface.address,
+        [address, IpAddr::from_str("fd00::1").unwrap()]
+    );
+    let peer = net.map_to_peer(&net.peers[1], &net.peers[0]).unwrap();
+    assert_eq!(
+        peer.allowed_ips[0],
+        IpNetwork::from_str("10.0.0.254/32").unwrap()
+    );
+
+    assert!(net
+        .check_static_address(IpAddr::from_str("10.0.1.1").unwrap())
+        .is_err());
+    assert!(net
+        .check_static_address(IpAddr::from_str("10.0.0.255").unwrap())
+        .is_err());
+    assert!(net
+        .check_address_collisions(&test_peer("new", 254))
+        .is_err());
+    assert!(net.check_address_collisions(&test_peer("new", 3)).is_ok());
+}
+
+#[test]
+fn test_allocation() {
+    let mut net = WireguardNetworkInfo::new(
+        "test",
+        vec![
+            IpNetwork::from_str("10.0.0.0/29").unwrap(),
+            IpNetwork::from_str("fd00::/120").unwrap(),
+        ],
+    );
+    assert_eq!(net.max_id(), 6);
+    assert_eq!(net.capacity(), 6);
+
+    let mut server = test_peer("server", 1);
+    server.addresses = vec![IpAddr::from_str("10.0.0.2").unwrap()];
+    net.peers = vec![server, test_peer("c", 3)];
+    assert_eq!(net.free_id(), Ok(4));
+    assert_eq!(net.capacity(), 3);
+
+    net.peers
+        .extend([4, 5, 6].iter().map(|id| test_peer("more", *id)));
+    assert!(net.free_id().is_err());
+    assert!(get_network_address(net.networks[0], 7).is_err());
+    assert!(get_network_address(net.networks[1], 255).is_ok());
+}
+
+#[test]
+fn test_endpoint_profiles() {
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    let mut server = test_peer("server", 1);
+    for (name, address) in [("v4", "1.2.3.4:51820"), ("lan", "192.168.1.2:51820")] {
+        server.set_endpoint(Endpoint {
+            name: name.into(),
+            address: address.into(),
+        })
+    }
+    let mut office = test_peer("office", 2);
+    office.flags.push(PeerFlag::EndpointProfile {
+        profile: "lan".into(),
+    });
+    net.peers = vec![server, office, test_peer("remote", 3)];
+
+    let endpoint = |viewer: &PeerInfo| net.map_to_peer(viewer, &net.peers[0]).unwrap().endpoint;
+    assert_eq!(endpoint(&net.peers[1]), Some("192.168.1.2:51820".into()));
+    assert_eq!(endpoint(&net.peers[2]), Some("1.2.3.4:51820".into()));
+}
+
+#[test]
+fn test_routes() {
+    let mut net =
+        WireguardNetworkInfo::new("test", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    let mut office = test_peer("office", 1);
+    office.flags.push(PeerFlag::Routes {
+        networks: vec![IpNetwork::from_str("192.168.10.0/24").unwrap()],
+        masquerade: Some("eth0".into()),
+    });
+    net.peers = vec![office, test_peer("laptop", 2)];
+    net.check_routes(&net.peers[0]).unwrap();
+
+    let config = net.get_configuration(&net.peers[1]).unwrap();
+    assert_eq!(
+        config.peers[0].allowed_ips,
+        ["10.0.0.1/32", "192.168.10.0/24"]
+            .iter()
+            .map(|n| IpNetwork::from_str(n).unwrap())
+            .collect::<Vec<_>>()
+    );
+    let interface = net.map_to_interface(&net.peers[0]).unwrap();
+    assert_eq!(
+        interface.pre_up_commands("test"),
+        Some("sysctl -w net.ipv4.ip_forward=1;iptables -t nat -C POSTROUTING -s 10.0.0.0/24 -o eth0 -j MASQUERADE 2>/dev/null || iptables -t nat -A POSTROUTING -s 10.0.0.0/24 -o eth0 -j MASQUERADE".into())
+    );
+
+    let mut home = net.peers[1].clone();
+    home.flags.push(PeerFlag::Routes {
+        networks: vec![IpNetwork::from_str("192.168.0.0/16").unwrap()],
+        masquerade: None,
+    });
+    assert_eq!(
+        net.check_routes(&home),
+        Err("Route 192.168.0.0/16 of laptop overlaps with route 192.168.10.0/24 of office".into())
+    );
 }
@@ -0,0 +1,188 @@
+// ! systemd-networkd .netdev and .network files
+// Private keys are never embedded, files exported with `secrets` command are referenced instead.
+
+use crate::configs::nix::KeyFileExportConfig;
+use crate::configs::*;
+
+pub struct NetworkdConf {}
+
+#[derive(Debug, Clone)]
+pub struct NetworkdExportConfig {
+    /// Where secrets are on a target machine
+    pub key_file: KeyFileExportConfig,
+}
+
+/// Returns names and contents of the .netdev and .network files
+pub fn write_files(
+    config: WireguardConfiguration,
+    export_options: NetworkdExportConfig,
+) -> Vec<(String, String)> {
+    let interface = config.interface;
+    let target_prefix = export_options.key_file.target_prefix;
+
+    let mut netdev = String::new();
+    netdev += "# Generated by wgbond\n";
+    netdev += "[NetDev]\n";
+    netdev += format!("Name={}\n", config.name).as_str();
+    netdev += "Kind=wireguard\n";
+    netdev += format!("Description=Wireguard network \"{}\"\n", config.name).as_str();
+
+    netdev += "\n[WireGuard]\n";
+    netdev += format!(
+        "PrivateKeyFile={}/wg-{}.ed25519.base64\n",
+        target_prefix, config.name
+    )
+    .as_str();
+    if let Some(port) = interface.port {
+        netdev += format!("ListenPort={}\n", port).as_str();
+    }
+    if let Some(mark) = interface.fw_mark {
+        netdev += format!("FirewallMark={}\n", mark).as_str();
+    }
+    // networkd adds routes for AllowedIPs only if RouteTable is set
+    match interface.table.as_deref() {
+        Some("off") => {}
+        None | Some("auto") => netdev += "RouteTable=main\n",
+        Some(table) => netdev += format!("RouteTable={}\n", table).as_str(),
+    }
+
+    for peer in config.peers.iter() {
+        netdev += "\n[WireGuardPeer]\n";
+        netdev += format!("# {}\n", peer.name).as_str();
+        netdev += format!("PublicKey={}\n", peer.public_key).as_str();
+        if peer.preshared_key.is_some() {
+            netdev += format!(
+                "PresharedKeyFile={}/wg-{}-{}.psk\n",
+                target_prefix, config.name, peer.name
+            )
+            .as_str();
+        }
+        if !peer.allowed_ips.is_empty() {
+            netdev += format!(
+                "AllowedIPs={}\n",
+                peer.allowed_ips
+                    .iter()
+                    .map(IpNetwork::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )
+            .as_str();
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            netdev += format!("Endpoint={}\n", endpoint).as_str();
+        }
+        if let Some(keepalive) = peer.persistent_keepalive {
+            netdev += format!("PersistentKeepalive={}\n", keepalive).as_str();
+        }
+    }
+
+    let mut network = String::new();
+    network += "# Generated by wgbond\n";
+    network += interface.skip_hooks("systemd-networkd").as_str();
+
+    network += "[Match]\n";
+    network += format!("Name={}\n", config.name).as_str();
+
+    network += "\n[Network]\n";
+    for address in interface.address.iter() {
+        // networkd masquerades traffic coming from prefixes of addresses of the interface
+        let prefix = interface
+            .masquerade
+            .iter()
+            .flat_map(|m| m.sources.iter())
+            .find(|source| source.contains(*address))
+            .map(IpNetwork::prefix)
+            .unwrap_or(if address.is_ipv4() { 32 } else { 128 });
+        network += format!("Address={}/{}\n", address, prefix).as_str();
+    }
+    for dns in interface.dns.iter() {
+        network += format!("DNS={}\n", dns).as_str();
+    }
+
+    if interface.forwarding.iter().any(IpNetwork::is_ipv4) {
+        network += "IPv4Forwarding=yes\n";
+    }
+    if interface.forwarding.iter().any(IpNetwork::is_ipv6) {
+        network += "IPv6Forwarding=yes\n";
+    }
+    if !interface.masquerade.is_empty() {
+        // Masqueraded traffic may leave through any interface, networkd can't narrow it down
+        let sources = interface
+            .masquerade
+            .iter()
+            .flat_map(|m| m.sources.iter())
+            .collect::<Vec<_>>();
+        let family = match (
+            sources.iter().any(|s| s.is_ipv4()),
+            sources.iter().any(|s| s.is_ipv6()),
+        ) {
+            (true, true) => "both",
+            (false, true) => "ipv6",
+            _ => "ipv4",
+        };
+        for masquerade in interface.masquerade.iter() {
+            network += format!(
+                "# Masquerade through {} is applied to all outgoing interfaces\n",
+                masquerade.interface
+            )
+            .as_str();
+        }
+        network += format!("IPMasquerade={}\n", family).as_str();
+    }
+
+    vec![
+        (format!("25-{}.netdev", config.name), netdev),
+        (format!("25-{}.network", config.name), network),
+    ]
+}
+
+impl ConfigType for NetworkdConf {
+    type ExportConfig = NetworkdExportConfig;
+
+    fn needs_secrets(_options: &NetworkdExportConfig) -> bool {
+        false
+    }
+
+    fn write_config(
+        config: WireguardConfiguration,
+        export_options: NetworkdExportConfig,
+    ) -> String {
+        write_files(config, export_options)
+            .into_iter()
+            .map(|(name, contents)| format!("### {}\n{}", name, contents))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[test]
+fn test_networkd_masquerade() {
+    let mut router = test_peer("router", 1);
+    router.flags.push(PeerFlag::Routes {
+        networks: vec![IpNetwork::from_str("fd01::/64").unwrap()],
+        masquerade: Some("eth0".into()),
+    });
+    let mut net = WireguardNetworkInfo::new("wg0", vec![IpNetwork::from_str("fd00::/64").unwrap()]);
+    net.peers = vec![router];
+    let config = net.get_configuration(&net.peers[0]).unwrap();
+    let private_key = config.interface.private_key.clone();
+    let files = write_files(
+        config,
+        NetworkdExportConfig {
+            key_file: KeyFileExportConfig {
+                target_prefix: "/run/keys".into(),
+            },
+        },
+    );
+
+    assert_eq!(files[0].0, "25-wg0.netdev");
+    assert!(files[0]
+        .1
+        .contains("PrivateKeyFile=/run/keys/wg-wg0.ed25519.base64\n"));
+    assert!(!files[0].1.contains(&private_key));
+    // Prefix of the address is what gets masqueraded
+    assert!(files[1].1.contains("Address=fd00::1/64\n"));
+    assert!(files[1].1.contains("IPMasquerade=ipv6\n"));
+    assert!(files[1].1.contains("IPv6Forwarding=yes\n"));
+    assert!(!files[1].1.contains("IPv4Forwarding"));
+}
@@ -0,0 +1,174 @@
+// ! NetworkManager keyfile
+// Save as /etc/NetworkManager/system-connections/<name>.nmconnection, owned by root with 0600 mode.
+
+use crate::configs::*;
+
+pub struct NMConf {}
+
+fn write_list(list: impl Iterator<Item = impl core::fmt::Display>) -> String {
+    list.map(|item| format!("{};", item)).collect::<String>()
+}
+
+/// Writes [ipv4] or [ipv6] section of the connection
+fn write_ip_section(
+    family: &str,
+    addresses: &[&IpAddr],
+    dns: &[&IpAddr],
+    has_default_route: bool,
+) -> String {
+    let mut built = format!("\n[{}]\n", family);
+    if addresses.is_empty() {
+        built += "method=disabled\n";
+        return built;
+    }
+    built += "method=manual\n";
+    for (i, address) in addresses.iter().enumerate() {
+        let prefix = if address.is_ipv4() { 32 } else { 128 };
+        built += format!("address{}={}/{}\n", i + 1, address, prefix).as_str();
+    }
+    if !dns.is_empty() {
+        built += format!("dns={}\n", write_list(dns.iter())).as_str();
+    }
+    built += format!("never-default={}\n", !has_default_route).as_str();
+    built
+}
+
+impl ConfigType for NMConf {
+    type ExportConfig = ();
+
+    fn write_config(config: WireguardConfiguration, _: ()) -> String {
+        let interface = config.interface;
+
+        let mut built = String::new();
+        built += "# Generated by wgbond\n";
+        built += interface.skip_hooks("NetworkManager").as_str();
+        for masquerade in interface.masquerade.iter() {
+            warn!("NetworkManager doesn't support masquerade, skipping it");
+            built += format!(
+                "# Masquerade through {} is not supported, skipped\n",
+                masquerade.interface
+            )
+            .as_str();
+        }
+        if !interface.forwarding.is_empty() {
+            warn!("NetworkManager doesn't enable forwarding, it has to be enabled separately");
+            built += "# Forwarding has to be enabled separately\n";
+        }
+
+        built += "[connection]\n";
+        built += format!("id={}\n", config.name).as_str();
+        built += "type=wireguard\n";
+        built += format!("interface-name={}\n", config.name).as_str();
+
+        built += "\n[wireguard]\n";
+        built += format!("private-key={}\n", interface.private_key).as_str();
+        if let Some(port) = interface.port {
+            built += format!("listen-port={}\n", port).as_str();
+        }
+        if let Some(mark) = interface.fw_mark {
+            built += format!("fwmark={}\n", mark).as_str();
+        }
+
+        for peer in config.peers.iter() {
+            built += format!("\n[wireguard-peer.{}]\n", peer.public_key).as_str();
+            if let Some(endpoint) = &peer.endpoint {
+                built += format!("endpoint={}\n", endpoint).as_str();
+            }
+            if let Some(psk) = &peer.preshared_key {
+                built += format!("preshared-key={}\n", psk).as_str();
+                built += "preshared-key-flags=0\n";
+            }
+            if let Some(keepalive) = peer.persistent_keepalive {
+                built += format!("persistent-keepalive={}\n", keepalive).as_str();
+            }
+            built += format!("allowed-ips={}\n", write_list(peer.allowed_ips.iter())).as_str();
+        }
+
+        let routes = config
+            .peers
+            .iter()
+            .flat_map(|peer| peer.allowed_ips.iter())
+            .collect::<Vec<_>>();
+        let (addresses_v4, addresses_v6): (Vec<_>, Vec<_>) =
+            interface.address.iter().partition(|a| a.is_ipv4());
+        let (dns_v4, dns_v6): (Vec<_>, Vec<_>) = interface.dns.iter().partition(|a| a.is_ipv4());
+
+        built += write_ip_section(
+            "ipv4",
+            &addresses_v4,
+            &dns_v4,
+            routes.iter().any(|r| r.is_ipv4() && r.prefix() == 0),
+        )
+        .as_str();
+        built += write_ip_section(
+            "ipv6",
+            &addresses_v6,
+            &dns_v6,
+            routes.iter().any(|r| r.is_ipv6() && r.prefix() == 0),
+        )
+        .as_str();
+
+        built
+    }
+}
+
+#[test]
+fn test_nm_default_route() {
+    let mut gateway = test_peer("gateway", 1);
+    gateway.endpoints = vec![Endpoint {
+        name: DEFAULT_ENDPOINT.into(),
+        address: "example.com:51820".into(),
+    }];
+    gateway.flags.push(PeerFlag::Gateway {
+        ignore_local_networks: false,
+        exclude: vec![],
+    });
+    let mut client = test_peer("client", 2);
+    client.flags = vec![
+        PeerFlag::UseGateway {
+            peer: 1,
+            proxy: ProxyConfig {
+                networks: vec![],
+                use_global_networks: false,
+                proxy_internet: true,
+            },
+        },
+        PeerFlag::DNS {
+            addresses: vec![IpAddr::from_str("10.0.0.1").unwrap()],
+        },
+    ];
+    let mut net =
+        WireguardNetworkInfo::new("wg0", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.peers = vec![gateway, client];
+    let config = net.get_configuration(&net.peers[1]).unwrap();
+
+    assert_eq!(
+        NMConf::write_config(config, ()),
+        format!(
+            "# Generated by wgbond
+[connection]
+id=wg0
+type=wireguard
+interface-name=wg0
+
+[wireguard]
+private-key={}
+
+[wireguard-peer.{}]
+endpoint=example.com:51820
+allowed-ips=0.0.0.0/0;10.0.0.1/32;
+
+[ipv4]
+method=manual
+address1=10.0.0.2/32
+dns=10.0.0.1;
+never-default=false
+
+[ipv6]
+method=disabled
+",
+            net.peers[1].private_key.as_ref().unwrap().expose().unwrap(),
+            net.peers[0].public_key
+        )
+    );
+}
@@ -17,15 +17,42 @@ pub struct NixExportConfig {
     pub use_keyfile: Option<KeyFileExportConfig>,
 }
 
+/// Quotes a value as Nix string, so hooks keep their backslashes and shell variables
+fn nix_string(value: impl core::fmt::Display) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .to_string()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
 impl ConfigType for NixConf {
     type ExportConfig = NixExportConfig;
 
+    fn needs_secrets(options: &NixExportConfig) -> bool {
+        options.use_keyfile.is_none()
+    }
+
+    fn supports_acl() -> bool {
+        true
+    }
+
     fn write_config(config: WireguardConfiguration, export_options: NixExportConfig) -> String {
-        let interface = config.interface;
+        let mut interface = config.interface;
+        // NixOS sets up NAT and forwarding on its own, only other masquerades are left to hooks
+        let nat_interface = interface.masquerade.first().map(|m| m.interface.clone());
+        let (nat, masquerade) = std::mem::take(&mut interface.masquerade)
+            .into_iter()
+            .partition::<Vec<_>, _>(|m| Some(&m.interface) == nat_interface.as_ref());
+        interface.masquerade = masquerade;
+        let forwarding = std::mem::take(&mut interface.forwarding);
 
         fn set_assign(key: &str, value: &Option<impl core::fmt::Display>) -> String {
             match value {
-                Some(val) => format!("{}=\"{}\";", key, val),
+                Some(val) => format!("{}={};", key, nix_string(val)),
                 _ => "".into(),
             }
         }
@@ -40,7 +67,7 @@ impl ConfigType for NixConf {
         let mut built = String::new();
         built += format!("{{networking.wg-quick.interfaces.\"{}\"={{", &config.name).as_str();
 
-        if let Some(KeyFileExportConfig { target_prefix }) = export_options.use_keyfile {
+        if let Some(KeyFileExportConfig { target_prefix }) = &export_options.use_keyfile {
             built += format!(
                 "privateKeyFile=\"{}/wg-{}.ed25519.base64\";",
                 target_prefix, &config.name
@@ -56,7 +83,7 @@ impl ConfigType for NixConf {
         where
             T: core::fmt::Display,
         {
-            format!("\"{}\"", thing)
+            nix_string(thing)
         }
 
         // Addresses
@@ -84,13 +111,15 @@ impl ConfigType for NixConf {
             .as_str()
         }
 
-        built += set_assign("preUp", &interface.pre_up).as_str();
-        built += set_assign("preDown", &interface.pre_down).as_str();
+        built += set_assign("preUp", &interface.pre_up_commands(&config.name)).as_str();
+        built += set_assign("preDown", &interface.pre_down_commands(&config.name)).as_str();
         built += set_assign("postUp", &interface.post_up).as_str();
         built += set_assign("postDown", &interface.post_down).as_str();
 
         // Peers
-        fn encode_peer(peer: &Peer) -> String {
+        let keyfile = &export_options.use_keyfile;
+        let network_name = &config.name;
+        let encode_peer = |peer: &Peer| -> String {
             let mut built = String::new();
             built += "{";
             built += set_assign("publicKey", &Some(&peer.public_key)).as_str();
@@ -104,11 +133,21 @@ impl ConfigType for NixConf {
             )
             .as_str();
             built += set_assign_raw("persistentKeepalive", &peer.persistent_keepalive).as_str();
-            built += set_assign("presharedKey", &peer.preshared_key).as_str();
+            if let Some(KeyFileExportConfig { target_prefix }) = keyfile {
+                if peer.preshared_key.is_some() {
+                    built += format!(
+                        "presharedKeyFile=\"{}/wg-{}-{}.psk\";",
+                        target_prefix, network_name, &peer.name
+                    )
+                    .as_str();
+                }
+            } else {
+                built += set_assign("presharedKey", &peer.preshared_key).as_str();
+            }
             built += set_assign("endpoint", &peer.endpoint).as_str();
             built += "}";
             built
-        }
+        };
 
         built += format!(
             "peers=[{}];",
@@ -123,6 +162,61 @@ impl ConfigType for NixConf {
 
         built += "};";
 
+        if let Some(external) = nat_interface {
+            let sources = nat.iter().flat_map(|m| m.sources.iter());
+            let v4 = sources
+                .clone()
+                .filter(|s| s.is_ipv4())
+                .map(wrap_string)
+                .collect::<Vec<_>>();
+            let v6 = sources
+                .filter(|s| s.is_ipv6())
+                .map(wrap_string)
+                .collect::<Vec<_>>();
+            built += "networking.nat={enable=true;";
+            built += set_assign("externalInterface", &Some(external)).as_str();
+            if !v4.is_empty() {
+                built += format!("internalIPs=[{}];", v4.join(" ")).as_str();
+            }
+            if !v6.is_empty() {
+                built += format!("enableIPv6=true;internalIPv6s=[{}];", v6.join(" ")).as_str();
+            }
+            built += "};";
+        }
+        if forwarding.iter().any(IpNetwork::is_ipv4) {
+            built += "boot.kernel.sysctl.\"net.ipv4.ip_forward\"=1;";
+        }
+        if forwarding.iter().any(IpNetwork::is_ipv6) {
+            built += "boot.kernel.sysctl.\"net.ipv6.conf.all.forwarding\"=1;";
+        }
+        if interface.firewall == Firewall::Nftables {
+            built += "networking.nftables.enable=true;";
+        }
+
         built
     }
 }
+
+#[test]
+fn test_nix_hooks() {
+    let mut interface = PeerInfo::derive_interface(&test_peer("router", 1)).unwrap();
+    interface.firewall = Firewall::Nftables;
+    interface.masquerade = vec![Masquerade {
+        interface: "eth0".into(),
+        sources: vec![IpNetwork::from_str("10.0.0.0/24").unwrap()],
+    }];
+    interface.masquerade.push(Masquerade {
+        interface: "eth1".into(),
+        sources: vec![IpNetwork::from_str("10.0.0.0/24").unwrap()],
+    });
+    interface.post_up = Some("echo \"${HOME}\"".into());
+    let config = WireguardConfiguration {
+        interface,
+        peers: vec![],
+        name: "wg0".into(),
+    };
+
+    let built = NixConf::write_config(config, NixExportConfig { use_keyfile: None });
+    assert!(built.contains("preUp=\"printf 'table inet wg_bond_wg0 {}\\\\nflush table"));
+    assert!(built.contains("postUp=\"echo \\\"\\${HOME}\\\"\";"));
+}
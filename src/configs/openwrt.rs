@@ -0,0 +1,194 @@
+// ! OpenWrt UCI commands
+// Running them again replaces the interface and its peers.
+
+use crate::configs::*;
+
+pub struct OpenWrtConf {}
+
+#[derive(Debug, Clone)]
+pub struct OpenWrtExportConfig {
+    /// Firewall zone to forward traffic of masquerading peers to, usually "wan".
+    /// Firewall is not touched if not set.
+    pub firewall_zone: Option<String>,
+}
+
+/// UCI section names may only contain letters, digits and underscores
+fn section_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn quote(value: impl core::fmt::Display) -> String {
+    format!("'{}'", value.to_string().replace('\'', "'\\''"))
+}
+
+trait UCIBuilder {
+    fn uci_set(&mut self, key: &str, value: impl core::fmt::Display);
+    fn uci_add_list(&mut self, key: &str, value: impl core::fmt::Display);
+}
+
+impl UCIBuilder for String {
+    fn uci_set(&mut self, key: &str, value: impl core::fmt::Display) {
+        *self += format!("uci set {}={}\n", key, quote(value)).as_str();
+    }
+
+    fn uci_add_list(&mut self, key: &str, value: impl core::fmt::Display) {
+        *self += format!("uci add_list {}={}\n", key, quote(value)).as_str();
+    }
+}
+
+impl ConfigType for OpenWrtConf {
+    type ExportConfig = OpenWrtExportConfig;
+
+    fn write_config(config: WireguardConfiguration, export_options: OpenWrtExportConfig) -> String {
+        let interface = config.interface;
+        let name = section_name(&config.name);
+        let net = format!("network.{}", name);
+
+        let mut built = String::new();
+        built += format!("# OpenWrt config for \"{}\"\n", config.name).as_str();
+        built += "# Generated by wgbond\n";
+        built += interface.skip_hooks("OpenWrt").as_str();
+
+        built += format!("uci -q delete {}\n", net).as_str();
+        built += format!(
+            "while uci -q delete network.@wireguard_{}[0]; do :; done\n",
+            name
+        )
+        .as_str();
+
+        built += format!("uci set {}=interface\n", net).as_str();
+        built.uci_set(&format!("{}.proto", net), "wireguard");
+        built.uci_set(&format!("{}.private_key", net), &interface.private_key);
+        if let Some(port) = interface.port {
+            built.uci_set(&format!("{}.listen_port", net), port);
+        }
+        if let Some(mark) = interface.fw_mark {
+            built.uci_set(&format!("{}.fwmark", net), mark);
+        }
+        for address in interface.address.iter() {
+            let prefix = if address.is_ipv4() { 32 } else { 128 };
+            built.uci_add_list(
+                &format!("{}.addresses", net),
+                format!("{}/{}", address, prefix),
+            );
+        }
+        for dns in interface.dns.iter() {
+            built.uci_add_list(&format!("{}.dns", net), dns);
+        }
+
+        let mut sections: Vec<String> = vec![];
+        for peer in config.peers.iter() {
+            // Peers in key grace period have an entry for every key
+            let base = format!("network.{}_{}", name, section_name(&peer.name));
+            let section = match sections.iter().filter(|s| **s == base).count() {
+                0 => base.clone(),
+                same => format!("{}_{}", base, same + 1),
+            };
+            sections.push(base);
+            built += format!("uci set {}=wireguard_{}\n", section, name).as_str();
+            built.uci_set(&format!("{}.description", section), &peer.name);
+            built.uci_set(&format!("{}.public_key", section), &peer.public_key);
+            if let Some(psk) = &peer.preshared_key {
+                built.uci_set(&format!("{}.preshared_key", section), psk);
+            }
+            if let Some(endpoint) = &peer.endpoint {
+                match split_endpoint(endpoint.clone()) {
+                    Ok((host, port)) => {
+                        let host = match host {
+                            Host::Domain(domain) => domain,
+                            Host::Ipv4(ip) => ip.to_string(),
+                            Host::Ipv6(ip) => ip.to_string(),
+                        };
+                        built.uci_set(&format!("{}.endpoint_host", section), host);
+                        built.uci_set(&format!("{}.endpoint_port", section), port);
+                    }
+                    Err(e) => warn!("Skipping endpoint of {}: {}", peer.name, e),
+                }
+            }
+            if let Some(keepalive) = peer.persistent_keepalive {
+                built.uci_set(&format!("{}.persistent_keepalive", section), keepalive);
+            }
+            let route = interface.table.as_deref() != Some("off");
+            built.uci_set(&format!("{}.route_allowed_ips", section), route as u8);
+            for allowed_ip in peer.allowed_ips.iter() {
+                built.uci_add_list(&format!("{}.allowed_ips", section), allowed_ip);
+            }
+        }
+        built += "uci commit network\n";
+
+        if let Some(dest) = export_options.firewall_zone {
+            let zone = format!("firewall.{}", name);
+            built += format!("uci -q delete {}\n", zone).as_str();
+            built += format!("uci set {}=zone\n", zone).as_str();
+            built.uci_set(&format!("{}.name", zone), &name);
+            built.uci_set(&format!("{}.input", zone), "ACCEPT");
+            built.uci_set(&format!("{}.output", zone), "ACCEPT");
+            built.uci_set(&format!("{}.forward", zone), "ACCEPT");
+            built.uci_add_list(&format!("{}.network", zone), &name);
+
+            let forwarding = format!("firewall.{}_{}", name, section_name(&dest));
+            built += format!("uci -q delete {}\n", forwarding).as_str();
+            if !interface.masquerade.is_empty() {
+                // Zones masquerade on their own, so the zone traffic leaves to should have masq enabled
+                for masquerade in interface.masquerade.iter() {
+                    built += format!(
+                        "# Masquerade through {} is done by \"{}\" zone\n",
+                        masquerade.interface, dest
+                    )
+                    .as_str();
+                }
+                built += format!("uci set {}=forwarding\n", forwarding).as_str();
+                built.uci_set(&format!("{}.src", forwarding), &name);
+                built.uci_set(&format!("{}.dest", forwarding), &dest);
+            }
+            built += "uci commit firewall\n";
+        }
+
+        built
+    }
+}
+
+#[test]
+fn test_openwrt_peer() {
+    let mut server = test_peer("server", 1);
+    server.endpoints = vec![Endpoint {
+        name: DEFAULT_ENDPOINT.into(),
+        address: "example.com:51820".into(),
+    }];
+    let mut net =
+        WireguardNetworkInfo::new("wg-home", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.peers = vec![server, test_peer("client", 2)];
+    let config = net.get_configuration(&net.peers[1]).unwrap();
+
+    assert_eq!(
+        OpenWrtConf::write_config(
+            config,
+            OpenWrtExportConfig {
+                firewall_zone: None
+            }
+        ),
+        format!(
+            "# OpenWrt config for \"wg-home\"
+# Generated by wgbond
+uci -q delete network.wg_home
+while uci -q delete network.@wireguard_wg_home[0]; do :; done
+uci set network.wg_home=interface
+uci set network.wg_home.proto='wireguard'
+uci set network.wg_home.private_key='{}'
+uci add_list network.wg_home.addresses='10.0.0.2/32'
+uci set network.wg_home_server=wireguard_wg_home
+uci set network.wg_home_server.description='server'
+uci set network.wg_home_server.public_key='{}'
+uci set network.wg_home_server.endpoint_host='example.com'
+uci set network.wg_home_server.endpoint_port='51820'
+uci set network.wg_home_server.route_allowed_ips='1'
+uci add_list network.wg_home_server.allowed_ips='10.0.0.1/32'
+uci commit network
+",
+            net.peers[1].private_key.as_ref().unwrap().expose().unwrap(),
+            net.peers[0].public_key
+        )
+    );
+}
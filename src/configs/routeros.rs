@@ -0,0 +1,230 @@
+// ! MikroTik RouterOS script
+// Paste into terminal or run with `/import`. Requires RouterOS v7.
+
+use crate::configs::*;
+
+pub struct RouterOSConf {}
+
+/// Quotes a value for RouterOS scripting language
+fn quote(value: impl core::fmt::Display) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .to_string()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$")
+    )
+}
+
+/// Splits endpoint into RouterOS endpoint-address and endpoint-port values
+fn endpoint_params(endpoint: &str) -> Result<String, String> {
+    let (host, port) = split_endpoint(endpoint.to_string())?;
+    let address = match host {
+        Host::Domain(domain) => domain,
+        Host::Ipv4(ip) => ip.to_string(),
+        Host::Ipv6(ip) => ip.to_string(),
+    };
+    Ok(format!(
+        " endpoint-address={} endpoint-port={}",
+        address, port
+    ))
+}
+
+impl ConfigType for RouterOSConf {
+    type ExportConfig = ();
+
+    fn write_config(config: WireguardConfiguration, _: ()) -> String {
+        let interface = config.interface;
+        let name = quote(&config.name);
+
+        let mut built = String::new();
+        built += format!("# RouterOS config for \"{}\"\n", config.name).as_str();
+        built += "# Generated by wgbond\n";
+
+        built += interface.skip_hooks("RouterOS").as_str();
+
+        built += "/interface wireguard\n";
+        built += format!(
+            "add name={} private-key={}",
+            name,
+            quote(&interface.private_key)
+        )
+        .as_str();
+        if let Some(port) = interface.port {
+            built += format!(" listen-port={}", port).as_str();
+        }
+        built += "\n";
+
+        built += "/interface wireguard peers\n";
+        for peer in config.peers.iter() {
+            built += format!(
+                "add interface={} public-key={} allowed-address={} comment={}",
+                name,
+                quote(&peer.public_key),
+                peer.allowed_ips
+                    .iter()
+                    .map(IpNetwork::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                quote(&peer.name)
+            )
+            .as_str();
+            if let Some(psk) = &peer.preshared_key {
+                built += format!(" preshared-key={}", quote(psk)).as_str();
+            }
+            if let Some(endpoint) = &peer.endpoint {
+                match endpoint_params(endpoint) {
+                    Ok(params) => built += params.as_str(),
+                    Err(e) => warn!("Skipping endpoint of {}: {}", peer.name, e),
+                }
+            }
+            if let Some(keepalive) = peer.persistent_keepalive {
+                built += format!(" persistent-keepalive={}s", keepalive).as_str();
+            }
+            built += "\n";
+        }
+
+        // Addresses are single hosts, routes are added for AllowedIPs, same as wg-quick does
+        for address in interface.address.iter() {
+            match address {
+                IpAddr::V4(ip) => {
+                    built +=
+                        format!("/ip address add address={}/32 interface={}\n", ip, name).as_str()
+                }
+                IpAddr::V6(ip) => {
+                    built += format!(
+                        "/ipv6 address add address={}/128 interface={} advertise=no\n",
+                        ip, name
+                    )
+                    .as_str()
+                }
+            }
+        }
+
+        if interface.table.as_deref() != Some("off") {
+            let table = match interface.table.as_deref() {
+                None | Some("auto") => "".into(),
+                Some(table) => format!(" routing-table={}", quote(table)),
+            };
+            let mut routes: Vec<IpNetwork> = vec![];
+            for route in config.peers.iter().flat_map(|peer| peer.allowed_ips.iter()) {
+                if !routes.contains(route) {
+                    routes.push(*route)
+                }
+            }
+            for route in routes {
+                let family = if route.is_ipv4() { "ip" } else { "ipv6" };
+                let command = format!(
+                    "/{} route add dst-address={} gateway={}{}\n",
+                    family, route, name, table
+                );
+                // Unlike wg-quick, RouterOS has no policy routing for default routes out of the box,
+                // so the tunnel would carry its own encrypted packets to the endpoint
+                if route.prefix() == 0 && table.is_empty() {
+                    warn!(
+                        "Default route {} is commented out, it needs a separate routing table and a route to the endpoint",
+                        route
+                    );
+                    built +=
+                        "# Default route would loop traffic to the endpoint into the tunnel.\n";
+                    built += "# Add it to a separate routing table with routing rules, or add a route to the endpoint first:\n";
+                    built += format!("# {}", command).as_str();
+                } else {
+                    built += command.as_str();
+                }
+            }
+        }
+
+        if !interface.dns.is_empty() {
+            warn!(
+                "DNS servers are commented out, they would replace DNS servers of the whole router"
+            );
+            built += "# Replaces DNS servers of the whole router, uncomment to use:\n";
+            built += format!(
+                "# /ip dns set servers={}\n",
+                interface
+                    .dns
+                    .iter()
+                    .map(IpAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )
+            .as_str();
+        }
+
+        for masquerade in interface.masquerade.iter() {
+            for source in masquerade.sources.iter() {
+                let family = if source.is_ipv4() { "ip" } else { "ipv6" };
+                built += format!(
+                    "/{} firewall nat add chain=srcnat action=masquerade src-address={} out-interface={}\n",
+                    family,
+                    source,
+                    quote(&masquerade.interface)
+                )
+                .as_str();
+            }
+        }
+
+        built
+    }
+}
+
+#[test]
+fn test_routeros_export() {
+    let mut router = test_peer("router", 1);
+    router.listen_port = Some(51820);
+    router.flags = vec![
+        PeerFlag::Masquerade {
+            interface: "ether1".into(),
+        },
+        PeerFlag::Keepalive { keepalive: 25 },
+    ];
+    let mut office = test_peer("office", 2);
+    office.endpoints = vec![Endpoint {
+        name: DEFAULT_ENDPOINT.into(),
+        address: "[2001:db8::1]:51820".into(),
+    }];
+    let mut net =
+        WireguardNetworkInfo::new("wg0", vec![IpNetwork::from_str("10.0.0.0/24").unwrap()]);
+    net.peers = vec![router, office];
+    let config = net.get_configuration(&net.peers[0]).unwrap();
+
+    assert_eq!(
+        RouterOSConf::write_config(config, ()),
+        format!(
+            "# RouterOS config for \"wg0\"
+# Generated by wgbond
+/interface wireguard
+add name=\"wg0\" private-key=\"{}\" listen-port=51820
+/interface wireguard peers
+add interface=\"wg0\" public-key=\"{}\" allowed-address=10.0.0.2/32 comment=\"office\" endpoint-address=2001:db8::1 endpoint-port=51820 persistent-keepalive=25s
+/ip address add address=10.0.0.1/32 interface=\"wg0\"
+/ip route add dst-address=10.0.0.2/32 gateway=\"wg0\"
+/ip firewall nat add chain=srcnat action=masquerade src-address=10.0.0.0/24 out-interface=\"ether1\"
+",
+            net.peers[0].private_key.as_ref().unwrap().expose().unwrap(),
+            net.peers[1].public_key
+        )
+    );
+
+    net.peers[1].flags.push(PeerFlag::Gateway {
+        ignore_local_networks: true,
+        exclude: vec![],
+    });
+    net.peers[0].flags.push(PeerFlag::UseGateway {
+        peer: 2,
+        proxy: ProxyConfig {
+            networks: vec![],
+            use_global_networks: false,
+            proxy_internet: true,
+        },
+    });
+    net.peers[0].flags.push(PeerFlag::DNS {
+        addresses: vec![IpAddr::from_str("10.0.0.2").unwrap()],
+    });
+    let gateway = net.get_configuration(&net.peers[0]).unwrap();
+    let gateway = RouterOSConf::write_config(gateway, ());
+    assert!(gateway.contains("\n# /ip route add dst-address=0.0.0.0/0 gateway=\"wg0\"\n"));
+    assert!(gateway.contains("\n# /ip dns set servers=10.0.0.2\n"));
+}
@@ -5,20 +5,23 @@ extern crate serde;
 extern crate serde_json;
 
 use crate::configs::check_endpoint;
+use crate::configs::keystore::{Keystore, PASSPHRASE_VAR};
 use crate::configs::nix::KeyFileExportConfig;
 use crate::configs::ConfigType;
 use ipnetwork::IpNetwork;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::net::IpAddr;
 use std::str::FromStr;
 
 use configs::conf::ConfFile;
+use configs::networkd::{self, NetworkdConf, NetworkdExportConfig};
+use configs::networkmanager::NMConf;
 use configs::nix::NixConf;
 use configs::nixops;
+use configs::openwrt::{OpenWrtConf, OpenWrtExportConfig};
+use configs::routeros::RouterOSConf;
 use configs::{hosts::export_hosts, qr::QRConfig};
 
-use clap;
-
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
@@ -29,55 +32,435 @@ mod configs;
 mod wg_tools;
 use std::iter::*;
 
-fn read_config(fname: &str) -> Result<configs::WireguardNetworkInfo, String> {
+/// Put into exported configs of peers with only a public key
+const PRIVATE_KEY_PLACEHOLDER: &str = "<PRIVATE KEY>";
+
+/// Reads config file, upgrading it to the current schema version.
+/// Returns descriptions of applied migrations along with the config.
+fn read_config(fname: &str) -> Result<(configs::WireguardNetworkInfo, Vec<String>), String> {
     debug!("Opening config from {}", fname);
 
-    match std::fs::OpenOptions::new()
+    let handle = std::fs::OpenOptions::new()
         .create(false)
         .read(true)
         .open(fname)
-    {
-        Ok(handle) => {
-            let result: Result<configs::WireguardNetworkInfo, serde_json::Error> =
-                serde_json::from_reader(std::io::BufReader::new(handle));
-            match result {
-                Ok(net) => Ok(net),
-                Err(serde_err) => Err(format!("Cannot deserialize config file, {}", serde_err)),
-            }
-        }
-        Err(fs_err) => Err(format!("Cannot open config file, {}", fs_err)),
+        .map_err(|fs_err| format!("Cannot open config file, {}", fs_err))?;
+    let mut value: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(handle))
+        .map_err(|serde_err| format!("Cannot parse config file, {}", serde_err))?;
+
+    let applied = configs::migrations::migrate(&mut value)?;
+    for migration in applied.iter() {
+        info!("Migrated config {}", migration);
+    }
+
+    match serde_json::from_value(value) {
+        Ok(net) => Ok((net, applied)),
+        Err(serde_err) => Err(format!(
+            "Cannot deserialize config file with schema version {}, {}",
+            configs::migrations::CURRENT_VERSION,
+            serde_err
+        )),
     }
 }
 
-fn save_config(cfg: &configs::WireguardNetworkInfo, fname: &str) -> Result<(), std::io::Error> {
-    std::fs::OpenOptions::new()
+/// Takes an advisory lock on `<config>.lock`, waiting for other wg-bond instances to finish.
+/// Lock is held until returned file is closed.
+fn lock_config(fname: &str) -> Result<std::fs::File, String> {
+    let lock_name = format!("{}.lock", fname);
+    let lock = std::fs::OpenOptions::new()
         .create(true)
         .write(true)
-        .truncate(true)
-        .open(fname)
-        .map(std::io::BufWriter::new)
-        .map(|writer| serde_json::to_writer_pretty(writer, cfg).unwrap())
+        .truncate(false)
+        .open(&lock_name)
+        .map_err(|e| format!("Cannot open lock file {}, {}", lock_name, e))?;
+
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            eprintln!("Waiting for another wg-bond to release {}...", lock_name);
+            lock.lock()
+                .map_err(|e| format!("Cannot lock {}, {}", lock_name, e))?;
+        }
+        Err(std::fs::TryLockError::Error(e)) => {
+            Err(format!("Cannot lock {}, {}", lock_name, e))?;
+        }
+    }
+    Ok(lock)
+}
+
+/// Writes config into a temporary file, which then atomically replaces the old one.
+/// Previous version of the config is kept as `<config>.bak`, unless it has unencrypted secrets
+/// and the new one doesn't.
+fn save_config(cfg: &configs::WireguardNetworkInfo, fname: &str) -> RVoid {
+    let mut serialized =
+        serde_json::to_vec_pretty(cfg).map_err(|e| format!("Cannot serialize config, {}", e))?;
+    serialized.push(b'\n');
+
+    let previous = std::fs::read(fname).ok();
+    if previous.as_ref() == Some(&serialized) {
+        return Ok(());
+    }
+
+    let tmp_name = format!("{}.tmp", fname);
+    let write_tmp = || -> std::io::Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        #[cfg(unix)]
+        {
+            // Config holds private keys
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp_name)?;
+        if let Ok(metadata) = std::fs::metadata(fname) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(&serialized)?;
+        file.sync_all()
+    };
+    if let Err(e) = write_tmp() {
+        let _ = std::fs::remove_file(&tmp_name);
+        Err(format!("Cannot write {}, {}", tmp_name, e))?;
+    }
+
+    if let Some(previous) = previous {
+        let bak_name = format!("{}.bak", fname);
+        let was_sealed = serde_json::from_slice::<serde_json::Value>(&previous)
+            .map(|v| !v["keystore"].is_null())
+            .unwrap_or(false);
+        if cfg.keystore.is_some() && !was_sealed {
+            // Backups from before the keystore hold secrets unencrypted
+            match std::fs::remove_file(&bak_name) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    warn!(
+                        "Cannot remove {}, it holds unencrypted secrets, {}",
+                        bak_name, e
+                    )
+                }
+                _ => {}
+            }
+        } else {
+            std::fs::copy(fname, &bak_name)
+                .map_err(|e| format!("Cannot back up config to {}, {}", bak_name, e))?;
+        }
+    }
+    std::fs::rename(&tmp_name, fname)
+        .map_err(|e| format!("Cannot replace {} with {}, {}", fname, tmp_name, e))?;
+
+    // Rename survives a crash only once the directory is synced too
+    #[cfg(unix)]
+    {
+        let dir = match std::path::Path::new(fname).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        std::fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| format!("Cannot sync {}, {}", dir.display(), e))?;
+    }
+    Ok(())
 }
 
-fn new_id(cfg: &configs::WireguardNetworkInfo) -> u128 {
-    cfg.peers.iter().map(|i| i.id).max().unwrap_or(0) + 1
+#[test]
+fn test_save_config() {
+    let dir = std::env::temp_dir().join(format!("wg-bond-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let fname = dir.join("wg-bond.json");
+    let fname = fname.to_str().unwrap();
+    let bak_name = format!("{}.bak", fname);
+    let serialized = |cfg: &configs::WireguardNetworkInfo| serde_json::to_string(cfg).unwrap();
+
+    let _lock = lock_config(fname).unwrap();
+    let mut cfg = test_network();
+    save_config(&cfg, fname).unwrap();
+    assert!(!std::path::Path::new(&bak_name).exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(fname).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let (read, applied) = read_config(fname).unwrap();
+    assert!(applied.is_empty());
+    assert_eq!(serialized(&read), serialized(&cfg));
+
+    cfg.name = "renamed".into();
+    save_config(&cfg, fname).unwrap();
+    assert_eq!(read_config(fname).unwrap().0.name, "renamed");
+    assert_eq!(read_config(&bak_name).unwrap().0.name, "test");
+    assert!(!std::path::Path::new(&format!("{}.tmp", fname)).exists());
+
+    // Unchanged config is not written, so the backup stays
+    save_config(&cfg, fname).unwrap();
+    assert_eq!(read_config(&bak_name).unwrap().0.name, "test");
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
 
 fn command_init_config(matches: &clap::ArgMatches) -> configs::WireguardNetworkInfo {
     let name: &str = matches.value_of("name").unwrap();
     let net: &str = matches.value_of("network").unwrap();
 
-    configs::WireguardNetworkInfo {
-        name: name.to_string(),
-        networks: vec![IpNetwork::from_str(net).unwrap()],
-        flags: vec![],
-        peers: vec![],
+    let mut flags = vec![];
+    if matches.is_present("preshared-keys") {
+        flags.push(configs::NetworkFlag::PresharedKeys)
+    }
+
+    let mut cfg = configs::WireguardNetworkInfo::new(name, vec![IpNetwork::from_str(net).unwrap()]);
+    cfg.flags = flags;
+    if matches.is_present("ipv6") {
+        cfg.networks.push(ula_prefix(matches).into());
+    }
+    cfg
+}
+
+/// Generates a ULA prefix, in subnet given with --ipv6-subnet
+fn ula_prefix(matches: &clap::ArgMatches) -> ipnetwork::Ipv6Network {
+    let subnet = matches
+        .value_of("ipv6-subnet")
+        .map(|s| u16::from_str_radix(s, 16).unwrap())
+        .unwrap_or(0);
+    wg_tools::gen_ula_prefix(subnet)
+}
+
+fn ula_params<'a>(subcommand: clap::Command<'a>, flag: &'a str) -> clap::Command<'a> {
+    subcommand
+        .arg(
+            clap::Arg::new(flag)
+                .long(flag)
+                .help("Add a random RFC 4193 unique local IPv6 /64 network"),
+        )
+        .arg(
+            clap::Arg::new("ipv6-subnet")
+                .long("ipv6-subnet")
+                .help("Subnet id of the /64 inside of the generated /48")
+                .value_name("HEX")
+                .requires(flag)
+                .validator(|s| u16::from_str_radix(s, 16).map(|_| ()))
+                .takes_value(true),
+        )
+}
+
+/// Turns a network flag on or off, according to a pair of CLI flags
+fn toggle_network_flag(
+    cfg: &mut configs::WireguardNetworkInfo,
+    flag: configs::NetworkFlag,
+    matches: &clap::ArgMatches,
+    on: &str,
+    off: &str,
+) {
+    if matches.is_present(on) && !cfg.has_flag(flag.as_ref()) {
+        cfg.flags.push(flag)
+    } else if matches.is_present(off) {
+        cfg.flags.retain(|f| f.as_ref() != flag.as_ref())
+    }
+}
+
+fn command_acl(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
+    match matches.subcommand() {
+        Some(("add", matches)) => {
+            let rule = configs::AclRule {
+                from: matches.value_of("from").unwrap().into(),
+                to: matches.value_of("to").unwrap().into(),
+                services: matches
+                    .values_of("service")
+                    .into_iter()
+                    .flatten()
+                    .map(configs::acl::Service::from_str)
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            cfg.check_acl_rule(&rule)?;
+            if cfg.acl.is_empty() {
+                eprintln!(
+                    "Warning: with the first rule, Centers drop all traffic between peers \
+                     which no rule allows. Add rules for other traffic which should still pass."
+                );
+            }
+            cfg.acl.push(rule);
+            Ok(())
+        }
+        Some(("rm", matches)) => {
+            let number =
+                usize::from_str(matches.value_of("number").unwrap()).map_err(|e| e.to_string())?;
+            if number == 0 || number > cfg.acl.len() {
+                Err(format!("No rule number {}", number))?;
+            }
+            cfg.acl.remove(number - 1);
+            if cfg.acl.is_empty() {
+                println!("No rules left, Centers forward all traffic between peers.");
+            }
+            Ok(())
+        }
+        _ => {
+            for (number, rule) in cfg.acl.iter().enumerate() {
+                println!("{}: {}", number + 1, rule);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Drops host bits, as addresses are computed by setting them
+fn network_prefix(network: &str) -> Result<IpNetwork, String> {
+    let network = IpNetwork::from_str(network).map_err(|e| e.to_string())?;
+    Ok(IpNetwork::new(network.network(), network.prefix()).unwrap())
+}
+
+fn command_network(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
+    if let Some(name) = matches.value_of("rename") {
+        cfg.name = name.into();
+    }
+
+    let mut added = matches
+        .values_of("add")
+        .into_iter()
+        .flatten()
+        .map(network_prefix)
+        .collect::<Result<Vec<_>, _>>()?;
+    if matches.is_present("add-ula") {
+        added.push(ula_prefix(matches).into());
+    }
+    for network in added {
+        if let Some(other) = cfg
+            .networks
+            .iter()
+            .find(|n| n.contains(network.network()) || network.contains(n.network()))
+        {
+            Err(format!("Network {} overlaps with {}", network, other))?;
+        }
+        cfg.networks.push(network);
+    }
+
+    for network in matches.values_of("remove").into_iter().flatten() {
+        let network = network_prefix(network)?;
+        let position = cfg
+            .networks
+            .iter()
+            .position(|n| *n == network)
+            .ok_or(format!("Network {} is not used", network))?;
+        cfg.networks.remove(position);
+        for peer in cfg.peers.iter_mut() {
+            peer.addresses.retain(|a| !network.contains(*a));
+        }
+    }
+    if cfg.networks.is_empty() {
+        Err("At least one network should be left.")?;
+    }
+
+    toggle_network_flag(
+        cfg,
+        configs::NetworkFlag::Centralized,
+        matches,
+        "centralized",
+        "no-centralized",
+    );
+    toggle_network_flag(
+        cfg,
+        configs::NetworkFlag::PresharedKeys,
+        matches,
+        "preshared-keys",
+        "no-preshared-keys",
+    );
+
+    if let Some(hub_name) = matches.value_of("primary-hub") {
+        let hub = cfg.by_name(hub_name).ok_or("No hub found by given name")?;
+        if !hub.has_flag("Center") {
+            Err(format!("Peer {} is not a Center", hub.name))?;
+        }
+        let flag = configs::NetworkFlag::PrimaryHub { peer: hub.id };
+        cfg.flags.retain(|f| f.as_ref() != flag.as_ref());
+        cfg.flags.push(flag);
+    } else if matches.is_present("no-primary-hub") {
+        cfg.flags.retain(|f| f.as_ref() != "PrimaryHub");
+    }
+
+    cfg.check_addresses()?;
+    for peer in cfg.peers.iter() {
+        cfg.check_routes(peer)?;
+    }
+
+    println!("Name: {}", cfg.name);
+    for network in cfg.networks.iter() {
+        println!("Network: {}", network);
     }
+    for flag in cfg.flags.iter() {
+        match flag {
+            configs::NetworkFlag::PrimaryHub { peer } => match cfg.by_id(*peer) {
+                Some(hub) => println!("Primary hub: {}", hub.name),
+                None => println!("Primary hub: #{}", peer),
+            },
+            _ => println!("Flag: {}", flag.as_ref()),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_network_command() {
+    let mut cfg = test_network();
+    let network = |cfg: &mut configs::WireguardNetworkInfo, args: &[&str]| {
+        let mut args = args.to_vec();
+        args.insert(0, "network");
+        command_network(
+            cfg,
+            &network_params(clap::Command::new("network")).get_matches_from(args),
+        )
+    };
+
+    network(&mut cfg, &["--rename", "office", "--add", "10.1.2.3/16"]).unwrap();
+    assert_eq!(cfg.name, "office");
+    assert_eq!(cfg.networks[1], IpNetwork::from_str("10.1.0.0/16").unwrap());
+    assert!(network(&mut cfg, &["--add", "10.1.5.0/24"]).is_err());
+
+    cfg.peers[0].addresses = vec![IpAddr::from_str("10.1.0.10").unwrap()];
+    network(&mut cfg, &["--remove", "10.1.7.7/16"]).unwrap();
+    assert_eq!(cfg.networks.len(), 1);
+    assert!(cfg.peers[0].addresses.is_empty());
+    assert!(network(&mut cfg, &["--remove", "10.0.0.0/24"]).is_err());
 }
 
-fn parse_peer_edit_command(peer: &mut configs::PeerInfo, matches: &clap::ArgMatches) -> RVoid {
-    if let Some(endpoint) = matches.value_of("endpoint") {
-        peer.endpoint = Some(check_endpoint(endpoint.to_string())?);
+fn parse_peer_edit_command(
+    cfg: &configs::WireguardNetworkInfo,
+    peer: &mut configs::PeerInfo,
+    matches: &clap::ArgMatches,
+) -> RVoid {
+    // Without a port forward, peer listens on port of its default endpoint.
+    // Other endpoints may be forwarded from elsewhere, like a LAN address of a router.
+    let default_port = |peer: &configs::PeerInfo| {
+        peer.endpoints
+            .iter()
+            .find(|e| e.name == configs::DEFAULT_ENDPOINT)
+            .and_then(|e| configs::get_port(e.address.clone()).ok())
+    };
+    // Ports differing from the default endpoint's one were set with -p, and stay
+    let follows_endpoint = peer.listen_port.is_none() || peer.listen_port == default_port(peer);
+    for endpoint in matches.values_of("endpoint").into_iter().flatten() {
+        peer.set_endpoint(parse_named_endpoint(endpoint)?);
+    }
+    for name in matches.values_of("remove-endpoint").into_iter().flatten() {
+        peer.endpoints.retain(|e| e.name != name);
+    }
+    if follows_endpoint {
+        peer.listen_port = default_port(peer);
+    }
+
+    if let Some(profile) = matches.value_of("endpoint-profile") {
+        peer.flags.insert(
+            0,
+            configs::PeerFlag::EndpointProfile {
+                profile: profile.into(),
+            },
+        )
+    }
+
+    if let Some(port) = matches.value_of("listen-port") {
+        peer.listen_port = Some(u16::from_str(port).map_err(|e| e.to_string())?);
+    }
+    if peer.listen_port.is_none() && !peer.endpoints.is_empty() {
+        warn!(
+            "{} has no default endpoint to take listen port from, set it with -p",
+            peer.name
+        );
     }
 
     if let Some(dns) = matches.values_of("dns") {
@@ -105,11 +488,94 @@ fn parse_peer_edit_command(peer: &mut configs::PeerInfo, matches: &clap::ArgMatc
         peer.flags.insert(0, configs::PeerFlag::Center)
     }
 
+    if let Some(groups) = matches.values_of("group") {
+        peer.flags.insert(
+            0,
+            configs::PeerFlag::Groups {
+                groups: groups.map(String::from).collect(),
+            },
+        )
+    }
+
+    if let Some(backend) = matches.value_of("firewall") {
+        peer.flags.insert(
+            0,
+            configs::PeerFlag::Firewall {
+                backend: configs::Firewall::from_str(backend)?,
+            },
+        )
+    }
+
+    if let Some(routes) = matches.values_of("route") {
+        peer.flags.insert(
+            0,
+            configs::PeerFlag::Routes {
+                networks: routes
+                    .map(|route| {
+                        IpNetwork::from_str(route)
+                            .map(|n| IpNetwork::new(n.network(), n.prefix()).unwrap())
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|f| f.to_string())?,
+                masquerade: matches.value_of("route-masquerade").map(String::from),
+            },
+        )
+    }
+
     if matches.is_present("gateway") {
         peer.flags.insert(
             0,
             configs::PeerFlag::Gateway {
                 ignore_local_networks: true,
+                exclude: matches
+                    .values_of("gateway-exclude")
+                    .map(|nets| nets.map(IpNetwork::from_str).collect::<Result<Vec<_>, _>>())
+                    .transpose()
+                    .map_err(|f| f.to_string())?
+                    .unwrap_or_default(),
+            },
+        )
+    }
+
+    if let Some(gateway_name) = matches.value_of("use-gateway") {
+        let gateway = cfg
+            .by_name(gateway_name)
+            .ok_or("No gateway found by given name")?;
+        if gateway.id == peer.id {
+            Err("Peer cannot use itself as a gateway!")?;
+        }
+        if !gateway.has_flag("Gateway") {
+            warn!("Peer {} is not marked as a gateway", gateway.name);
+        }
+
+        peer.flags.insert(
+            0,
+            configs::PeerFlag::UseGateway {
+                peer: gateway.id,
+                proxy: configs::ProxyConfig {
+                    networks: matches
+                        .values_of("proxy-network")
+                        .map(|nets| nets.map(IpNetwork::from_str).collect::<Result<Vec<_>, _>>())
+                        .transpose()
+                        .map_err(|f| f.to_string())?
+                        .unwrap_or_default(),
+                    use_global_networks: matches.is_present("proxy-global"),
+                    proxy_internet: matches.is_present("proxy-internet"),
+                },
+            },
+        )
+    }
+
+    if let Some(segments) = matches.values_of("segment") {
+        peer.flags.insert(
+            0,
+            configs::PeerFlag::Segment {
+                mask: segments
+                    .map(|n| u8::from_str(n).map(|n| 1u128 << n))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|f| f.to_string())?
+                    .into_iter()
+                    .fold(0, |mask, bit| mask | bit),
             },
         )
     }
@@ -128,26 +594,62 @@ fn parse_peer_edit_command(peer: &mut configs::PeerInfo, matches: &clap::ArgMatc
 
     peer.flags.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
     peer.flags.dedup_by(|a, b| a.as_ref() == b.as_ref());
+    if matches.is_present("no-routes") {
+        peer.flags.retain(|f| f.as_ref() != "Routes");
+    }
+    if matches.is_present("no-groups") {
+        peer.flags.retain(|f| f.as_ref() != "Groups");
+    }
+    if matches.is_present("no-segment") {
+        peer.flags.retain(|f| f.as_ref() != "Segment");
+    }
+
+    if matches.is_present("no-static-address") {
+        peer.addresses.clear();
+    }
+    if let Some(addresses) = matches.values_of("address") {
+        for address in addresses {
+            let address = IpAddr::from_str(address).map_err(|e| e.to_string())?;
+            let network = cfg.check_static_address(address)?;
+            peer.addresses.retain(|a| !network.contains(*a));
+            peer.addresses.push(address);
+        }
+    }
+    cfg.check_address_collisions(peer)?;
+    cfg.check_routes(peer)?;
 
     Ok(())
 }
 
 fn command_new_peer(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
-    let peer_id = new_id(cfg);
+    let peer_id = cfg.free_id()?;
     let name: String = matches.value_of("name").unwrap().into();
     if cfg.by_name(&name).is_some() {
         Err("Peer with that name already exist!")?;
     }
 
+    let (private_key, public_key) = match matches.value_of("public-key") {
+        Some(public_key) => (None, public_key.to_string()),
+        None => {
+            let private_key = wg_tools::gen_private_key();
+            let public_key = wg_tools::gen_public_key(&private_key)?;
+            (Some(private_key.into()), public_key)
+        }
+    };
     let mut peer = configs::PeerInfo {
         name,
-        endpoint: None,
+        endpoints: vec![],
         id: peer_id,
-        private_key: wg_tools::gen_private_key(),
+        public_key,
+        private_key,
         flags: vec![],
+        listen_port: None,
+        addresses: vec![],
+        key_created: Some(configs::unix_time()),
+        key_history: vec![],
     };
 
-    parse_peer_edit_command(&mut peer, matches)?;
+    parse_peer_edit_command(cfg, &mut peer, matches)?;
 
     cfg.peers.append(&mut vec![peer]);
 
@@ -159,44 +661,200 @@ fn command_new_peer(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::Arg
 fn command_list_peers(cfg: &configs::WireguardNetworkInfo, _: &clap::ArgMatches) -> RVoid {
     // TODO: replace with some table lib
     println!(
-        "{peer_name:>12}   {peer_ip:30}   {endpoint:15}",
+        "{peer_name:>12}   {peer_ip:30}   {endpoint:30}   {key_age:8}",
         peer_name = "Name",
         peer_ip = "IP",
-        endpoint = "Endpoint"
+        endpoint = "Endpoint",
+        key_age = "Key age"
     );
+    let now = configs::unix_time();
     for peer in cfg.peers.iter() {
-        let wg_peer = cfg.map_to_interface(peer)?;
+        let addresses = cfg.peer_addresses(peer)?;
         println!(
-            "{name:>12}   {ip:30}   {endpoint:15}",
+            "{name:>12}   {ip:30}   {endpoint:30}   {key_age:8}",
             name = peer.name,
-            ip = wg_peer
-                .address
+            ip = addresses
                 .iter()
                 .map(|a| a.to_string())
                 .collect::<Vec<_>>()
                 .join(", "), // if it doesn't unwrap, something is really bad on our side
-            endpoint = peer.endpoint.clone().unwrap_or_else(|| "".into())
+            endpoint = peer
+                .endpoints
+                .iter()
+                .map(|e| match e.name.as_str() {
+                    configs::DEFAULT_ENDPOINT => e.address.clone(),
+                    name => format!("{}={}", name, e.address),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            key_age = match peer.key_created {
+                Some(created) => format!("{}d", now.saturating_sub(created) / 86400),
+                None => "unknown".into(),
+            }
         );
     }
+    println!("\n{} more peers can be added", cfg.capacity());
+    Ok(())
+}
+
+fn command_rotate(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
+    let grace_until = matches
+        .value_of("grace")
+        .map(|days| u64::from_str(days).map_err(|e| e.to_string()))
+        .transpose()?
+        .map(|days| configs::unix_time() + days * 86400);
+
+    let names: Vec<String> = if matches.is_present("all") {
+        cfg.peers.iter().map(|p| p.name.clone()).collect()
+    } else {
+        vec![matches.value_of("name").unwrap().into()]
+    };
+
+    for name in names {
+        let peer = cfg.by_name_mut(&name).ok_or("No peer with this name.")?;
+        if matches.is_present("confirm") {
+            peer.confirm_key();
+            println!(
+                "New key of {} is confirmed, old keys are not exported",
+                name
+            );
+            continue;
+        }
+        match matches.value_of("public-key") {
+            Some(public_key) => peer.rotate_key(None, public_key.into(), grace_until),
+            None if peer.private_key.is_none() => {
+                let message = format!(
+                    "Peer {} has only a public key, rotate it with --public-key.",
+                    name
+                );
+                if matches.is_present("all") {
+                    println!("{}", message);
+                } else {
+                    Err(message)?;
+                }
+            }
+            None => {
+                let private_key = wg_tools::gen_private_key();
+                let public_key = wg_tools::gen_public_key(&private_key)?;
+                peer.rotate_key(Some(private_key.into()), public_key, grace_until);
+                println!("Rotated key of {}", name);
+            }
+        }
+    }
     Ok(())
 }
 
 fn command_edit_peer(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
     let name: String = matches.value_of("name").unwrap().into();
-    let mut peer = cfg.by_name_mut(&name).ok_or("No peer with this name.")?;
+    let mut peer = cfg.by_name(&name).ok_or("No peer with this name.")?.clone();
+
+    parse_peer_edit_command(cfg, &mut peer, matches)?;
 
-    parse_peer_edit_command(&mut peer, matches)?;
+    *cfg.by_name_mut(&name).unwrap() = peer;
 
     Ok(())
 }
 
-fn command_export<C: ConfigType>(
+/// Runs `edit` subcommand with given arguments
+#[cfg(test)]
+fn edit(cfg: &mut configs::WireguardNetworkInfo, args: &[&str]) -> RVoid {
+    let mut args = args.to_vec();
+    args.insert(0, "edit");
+    let command = edit_params(clap::Command::new("edit")).arg(clap::Arg::new("name"));
+    command_edit_peer(cfg, &command.get_matches_from(args))
+}
+
+#[test]
+fn test_edit_endpoints() {
+    let mut cfg = test_network();
+
+    // Port of a LAN endpoint is forwarded from the router, so it's not the listen port
+    edit(&mut cfg, &["server", "-e", "lan=192.168.1.2:40000"]).unwrap();
+    assert_eq!(cfg.peers[0].listen_port, None);
+    edit(
+        &mut cfg,
+        &[
+            "server",
+            "-e",
+            "lan=192.168.1.2:40000,vpn.example.com:51820",
+        ],
+    )
+    .unwrap();
+    assert_eq!(cfg.peers[0].listen_port, Some(51820));
+    assert_eq!(cfg.peers[0].endpoints.len(), 2);
+    assert_eq!(
+        cfg.peers[0].endpoint(Some("lan")),
+        Some("192.168.1.2:40000")
+    );
+
+    edit(
+        &mut cfg,
+        &["server", "-p", "5000", "--remove-endpoint", "lan"],
+    )
+    .unwrap();
+    assert_eq!(cfg.peers[0].listen_port, Some(5000));
+    assert_eq!(
+        cfg.peers[0].endpoint(Some("lan")),
+        Some("vpn.example.com:51820")
+    );
+    // Port given with -p outlives changes of the endpoint
+    edit(&mut cfg, &["server", "-e", "vpn.example.com:2000"]).unwrap();
+    assert_eq!(cfg.peers[0].listen_port, Some(5000));
+}
+
+#[test]
+fn test_edit_listen_port() {
+    let mut cfg = test_network();
+
+    edit(&mut cfg, &["server", "-e", "1.1.1.1:1000"]).unwrap();
+    assert_eq!(cfg.peers[0].listen_port, Some(1000));
+    edit(&mut cfg, &["server", "-e", "1.1.1.1:2000"]).unwrap();
+    assert_eq!(cfg.peers[0].listen_port, Some(2000));
+    edit(&mut cfg, &["server", "--remove-endpoint", "default"]).unwrap();
+    assert_eq!(cfg.peers[0].listen_port, None);
+}
+
+#[test]
+fn test_edit_segments() {
+    let mut cfg = test_network();
+    edit(&mut cfg, &["server", "-S", "0,2"]).unwrap();
+    assert!(matches!(
+        cfg.peers[0].flags[..],
+        [configs::PeerFlag::Segment { mask: 0b101 }]
+    ));
+    edit(&mut cfg, &["server", "--no-segment"]).unwrap();
+    assert!(cfg.peers[0].flags.is_empty());
+}
+
+/// Builds configuration of a peer given to an export command
+/// With `template`, peers with only a public key get a placeholder instead of the private key
+fn export_configuration(
     cfg: &configs::WireguardNetworkInfo,
     matches: &clap::ArgMatches,
-    export_options: C::ExportConfig,
-) -> RVoid {
+    template: bool,
+) -> Result<configs::WireguardConfiguration, String> {
     let name: String = matches.value_of("name").unwrap().into();
-    let peer = cfg.by_name(&name).ok_or("No peer found with this name.")?;
+    let mut peer = cfg
+        .by_name(&name)
+        .ok_or("No peer found with this name.")?
+        .clone();
+    if peer.private_key.is_none() {
+        if !template {
+            Err(format!(
+                "Peer {} has only a public key, so its config can't be exported. \
+                 Use --template to put a placeholder instead of the private key.",
+                peer.name
+            ))?;
+        }
+        peer.private_key = Some(PRIVATE_KEY_PLACEHOLDER.to_string().into());
+    }
+    if let Some(profile) = matches.value_of("endpoint-profile") {
+        peer.flags.retain(|f| f.as_ref() != "EndpointProfile");
+        peer.flags.push(configs::PeerFlag::EndpointProfile {
+            profile: profile.into(),
+        });
+    }
+    let peer = &peer;
 
     let newcfg = &mut cfg.clone();
 
@@ -221,13 +879,213 @@ fn command_export<C: ConfigType>(
         };
     };
 
-    println!(
-        "{}",
-        C::write_config(newcfg.get_configuration(peer)?, export_options)
+    newcfg.get_configuration(peer)
+}
+
+#[cfg(test)]
+fn test_network() -> configs::WireguardNetworkInfo {
+    let mut cfg = configs::WireguardNetworkInfo::new(
+        "test",
+        vec![IpNetwork::from_str("10.0.0.0/24").unwrap()],
     );
+    let mut phone = configs::test_peer("phone", 2);
+    phone.private_key = None;
+    cfg.peers = vec![configs::test_peer("server", 1), phone];
+    cfg
+}
+
+#[test]
+fn test_export_public_key_only() {
+    let cfg = test_network();
+    let export = |args: &[&str]| {
+        let matches = export_params(clap::Command::new("conf")).get_matches_from(args);
+        export_configuration(&cfg, &matches, matches.is_present("template"))
+            .map(|c| ConfFile::write_config(c, ()))
+    };
+
+    let server = export(&["conf", "server"]).unwrap();
+    assert!(server.contains(&format!("PublicKey = {}", cfg.peers[1].public_key)));
+    assert!(server.contains("AllowedIPs = 10.0.0.2/32"));
+
+    assert!(export(&["conf", "phone"]).is_err());
+    let template = export(&["conf", "phone", "--template"]).unwrap();
+    assert!(template.contains(&format!("PrivateKey = {}", PRIVATE_KEY_PLACEHOLDER)));
+    assert!(template.contains(&format!("PublicKey = {}", cfg.peers[0].public_key)));
+}
+
+#[test]
+fn test_verify_public_key_only() {
+    let cfg = test_network();
+    let dump = std::env::temp_dir().join(format!("wg-bond-verify-{}", std::process::id()));
+    std::fs::write(
+        &dump,
+        format!(
+            "(none)\t{}\t0\toff\n{}\t(none)\t(none)\t10.0.0.1/32\t0\t0\t0\toff\n",
+            cfg.peers[1].public_key, cfg.peers[0].public_key
+        ),
+    )
+    .unwrap();
+    let matches = export_params(clap::Command::new("verify"))
+        .arg(clap::Arg::new("input").short('i').takes_value(true))
+        .get_matches_from(vec!["verify", "phone", "-i", dump.to_str().unwrap()]);
+    let result = command_verify(&cfg, &matches);
+    std::fs::remove_file(&dump).unwrap();
+    assert_eq!(result, Ok(()));
+}
+
+/// Asks for keystore passphrase, unless it's given in the environment
+fn read_passphrase(confirm: bool) -> Result<String, String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    let prompt = |text: &str| {
+        rpassword::prompt_password(text).map_err(|e| format!("Cannot read passphrase, {}", e))
+    };
+    let passphrase = prompt("Keystore passphrase: ")?;
+    if confirm && prompt("Repeat passphrase: ")? != passphrase {
+        Err("Passphrases don't match")?;
+    }
+    Ok(passphrase)
+}
+
+/// Copy of the network for an export.
+/// Encrypted secrets are decrypted if the export needs them, and blanked otherwise.
+fn with_secrets(
+    cfg: &configs::WireguardNetworkInfo,
+    needed: bool,
+) -> Result<configs::WireguardNetworkInfo, String> {
+    let mut cfg = cfg.clone();
+    if !needed {
+        cfg.redact_secrets();
+    } else if cfg.is_sealed() {
+        cfg.unseal_secrets(&read_passphrase(false)?)?;
+    }
+    Ok(cfg)
+}
+
+fn command_keystore(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
+    match matches.subcommand() {
+        Some(("enable", _)) => {
+            if cfg.keystore.is_some() {
+                Err("Keystore is already enabled.")?;
+            }
+            let passphrase = read_passphrase(true)?;
+            if passphrase.is_empty() {
+                Err("Passphrase should not be empty.")?;
+            }
+            // Secrets are sealed right before saving
+            cfg.keystore = Some(Keystore::new(&passphrase)?);
+            Ok(())
+        }
+        Some(("disable", _)) => {
+            if cfg.keystore.is_none() {
+                Err("Keystore is not enabled.")?;
+            }
+            cfg.unseal_secrets(&read_passphrase(false)?)?;
+            cfg.keystore = None;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fails for Centers of networks with access control rules, if the export would forward
+/// everything instead
+fn check_acl<C: ConfigType>(config: &configs::WireguardConfiguration) -> RVoid {
+    if config.interface.acl.is_some() && !C::supports_acl() {
+        Err(
+            "This export can't set up access control rules this Center enforces. \
+             Use conf or nix export, or remove the rules with `acl rm`.",
+        )?;
+    }
+    Ok(())
+}
+
+fn command_export<C: ConfigType>(
+    cfg: &configs::WireguardNetworkInfo,
+    matches: &clap::ArgMatches,
+    export_options: C::ExportConfig,
+) -> RVoid {
+    let cfg = &with_secrets(cfg, C::needs_secrets(&export_options))?;
+    let config = export_configuration(cfg, matches, matches.is_present("template"))?;
+    check_acl::<C>(&config)?;
+    println!("{}", C::write_config(config, export_options));
+    Ok(())
+}
+
+fn command_export_networkd(
+    cfg: &configs::WireguardNetworkInfo,
+    matches: &clap::ArgMatches,
+) -> RVoid {
+    let export_options = NetworkdExportConfig {
+        key_file: KeyFileExportConfig {
+            target_prefix: matches.value_of("secrets-prefix").unwrap().into(),
+        },
+    };
+    let cfg = &with_secrets(cfg, NetworkdConf::needs_secrets(&export_options))?;
+
+    if let Some(dir) = matches.value_of("output") {
+        let config = export_configuration(cfg, matches, matches.is_present("template"))?;
+        check_acl::<NetworkdConf>(&config)?;
+        for (name, contents) in networkd::write_files(config, export_options) {
+            std::fs::write(format!("{}/{}", dir, name), contents).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    } else {
+        command_export::<NetworkdConf>(cfg, matches, export_options)
+    }
+}
+
+fn command_import(cfg: &mut configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
+    let files = matches
+        .values_of("files")
+        .unwrap()
+        .map(|path| {
+            let name = std::path::Path::new(path)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .ok_or(format!("Cannot get peer name from {}", path))?;
+            let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            Ok((name.to_string(), text))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    for line in configs::import::import_configs(cfg, &files)? {
+        println!("{}", line);
+    }
     Ok(())
 }
 
+fn command_verify(cfg: &configs::WireguardNetworkInfo, matches: &clap::ArgMatches) -> RVoid {
+    // Keys are compared by public keys, so neither sealed secrets nor private keys are needed
+    let cfg = &with_secrets(cfg, false)?;
+    let expected = export_configuration(cfg, matches, true)?;
+    let public_key = &cfg
+        .by_name(matches.value_of("name").unwrap())
+        .ok_or("No peer found with this name.")?
+        .public_key;
+
+    let mut text = String::new();
+    match matches.value_of("input") {
+        Some("-") | None => std::io::stdin().read_to_string(&mut text),
+        Some(path) => std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut text)),
+    }
+    .map_err(|e| format!("Cannot read interface state, {}", e))?;
+
+    let actual = configs::drift::parse_state(&cfg.name, &text)?;
+    let differences = configs::drift::compare(&expected, public_key, &actual);
+    for difference in differences.iter() {
+        println!("{}", difference);
+    }
+
+    if differences.is_empty() {
+        println!("No differences found.");
+        Ok(())
+    } else {
+        Err(format!("{} differences found.", differences.len()))
+    }
+}
+
 fn command_export_secrets(
     cfg: &configs::WireguardNetworkInfo,
     matches: &clap::ArgMatches,
@@ -235,27 +1093,89 @@ fn command_export_secrets(
     let export_dir = matches.value_of("target").expect("no panik");
     for peer in &cfg.peers {
         std::fs::create_dir_all(format!("{}/{}", export_dir, peer.name))?;
-        let mut f = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(format!(
-                "{}/{}/wg-{}.ed25519.base64",
-                export_dir, peer.name, cfg.name
-            ))?;
-        f.write(peer.private_key.clone().as_bytes())?;
+        if let Some(private_key) = &peer.private_key {
+            let mut f = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(format!(
+                    "{}/{}/wg-{}.ed25519.base64",
+                    export_dir, peer.name, cfg.name
+                ))?;
+            f.write_all(
+                private_key
+                    .expose()
+                    .map_err(std::io::Error::other)?
+                    .as_bytes(),
+            )?;
+        }
+
+        for other in &cfg.peers {
+            if let Some(psk) = cfg.preshared_key(peer.id, other.id) {
+                let mut f = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(format!(
+                        "{}/{}/wg-{}-{}.psk",
+                        export_dir, peer.name, cfg.name, other.name
+                    ))?;
+                f.write_all(psk.expose().map_err(std::io::Error::other)?.as_bytes())?;
+            }
+        }
     }
     Ok(())
 }
 
+fn check_public_key(key: &str) -> Result<(), String> {
+    base64::decode(key)
+        .ok()
+        .filter(|k| k.len() == 32)
+        .map(|_| ())
+        .ok_or_else(|| "Expected base64 of a 32 byte key".into())
+}
+
+/// Parses `[NAME=]ADDRESS:PORT`, endpoints without a name are default ones
+fn parse_named_endpoint(text: &str) -> Result<configs::Endpoint, String> {
+    let (name, address) = match text.split_once('=') {
+        Some((name, address)) => (name, address),
+        None => (configs::DEFAULT_ENDPOINT, text),
+    };
+    Ok(configs::Endpoint {
+        name: name.into(),
+        address: check_endpoint(address.to_string())?,
+    })
+}
+
 fn edit_params<'a>(subcommand: clap::Command<'a>) -> clap::Command<'a> {
     subcommand
     .arg(clap::Arg::new("endpoint")
             .short('e')
             .long("endpoint")
-            .help("Endpoint address of a peer")
-            .value_name("ADDRESS:PORT")
-            .validator(
-                |f| check_endpoint(f.to_string()).map(|_| ()))
+            .help("Endpoint address of a peer. Named endpoints are used by peers with matching --endpoint-profile")
+            .value_name("[NAME=]ADDRESS:PORT")
+            .use_value_delimiter(true)
+            .validator(|f| parse_named_endpoint(f).map(|_| ()))
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("remove-endpoint")
+            .long("remove-endpoint")
+            .help("Remove named endpoints of a peer")
+            .value_name("NAME")
+            .use_value_delimiter(true)
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("endpoint-profile")
+            .long("endpoint-profile")
+            .help("Connect to peers using their endpoints with this name, if they have one")
+            .value_name("NAME")
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("listen-port")
+            .short('p')
+            .long("listen-port")
+            .help("Port a peer listens on, if it differs from port of its default endpoint")
+            .value_name("PORT")
+            .validator(|p| u16::from_str(p).map(|_| ()))
             .takes_value(true)
         )
         .arg(clap::Arg::new("dns")
@@ -276,6 +1196,69 @@ fn edit_params<'a>(subcommand: clap::Command<'a>) -> clap::Command<'a> {
             .help("Whether this peer is a gateway. You may also need -M.")
             .takes_value(false)
         )
+        .arg(clap::Arg::new("use-gateway")
+            .short('g')
+            .long("use-gateway")
+            .help("Connect only to the given gateway, and route traffic through it")
+            .takes_value(true)
+            .value_name("GATEWAY NAME")
+        )
+        .arg(clap::Arg::new("proxy-network")
+            .long("proxy-network")
+            .help("Additional networks to route through the gateway")
+            .value_name("IP/MASK,IP/MASK")
+            .use_value_delimiter(true)
+            .requires("use-gateway")
+            .validator(|f| IpNetwork::from_str(f)
+                .map(|_| ())
+                .map_err(|f| f.to_string())
+            )
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("proxy-global")
+            .long("proxy-global")
+            .help("Route whole internet except local networks through the gateway")
+            .requires("use-gateway")
+            .takes_value(false)
+        )
+        .arg(clap::Arg::new("proxy-internet")
+            .long("proxy-internet")
+            .help("Route all traffic through the gateway")
+            .requires("use-gateway")
+            .takes_value(false)
+        )
+        .arg(clap::Arg::new("segment")
+            .short('S')
+            .long("segment")
+            .help("Segments this peer belongs to. Peers see each other only if they share a segment.")
+            .value_name("N,N")
+            .use_value_delimiter(true)
+            .validator(|v|
+                match u8::from_str(v) {
+                    Ok(n) if n < 128 => Ok(()),
+                    _ => Err("Segment should be a number from 0 to 127.".to_string()),
+                }
+            )
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("no-segment")
+            .long("no-segment")
+            .help("Remove this peer from all segments, so it sees every peer again")
+            .conflicts_with("segment")
+            .takes_value(false)
+        )
+        .arg(clap::Arg::new("gateway-exclude")
+            .long("gateway-exclude")
+            .help("Networks clients should not route through this gateway, in addition to local ones")
+            .value_name("IP/MASK,IP/MASK")
+            .use_value_delimiter(true)
+            .requires("gateway")
+            .validator(|f| IpNetwork::from_str(f)
+                .map(|_| ())
+                .map_err(|f| f.to_string())
+            )
+            .takes_value(true)
+        )
         .arg(clap::Arg::new("nixops")
             .short('N')
             .long("nixops")
@@ -291,10 +1274,69 @@ fn edit_params<'a>(subcommand: clap::Command<'a>) -> clap::Command<'a> {
         .arg(clap::Arg::new("masquerade")
             .short('M')
             .long("masquerade")
-            .help("Whether to enable iptables masquerade on this peer.")
+            .help("Whether to enable masquerade on this peer.")
+            .takes_value(true)
+            .value_name("INTERFACE")
+        )
+        .arg(clap::Arg::new("group")
+            .long("group")
+            .help("Groups access control rules refer to this peer by")
+            .value_name("GROUP,GROUP")
+            .use_value_delimiter(true)
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("no-groups")
+            .long("no-groups")
+            .help("Remove this peer from all groups")
+            .conflicts_with("group")
+            .takes_value(false)
+        )
+        .arg(clap::Arg::new("firewall")
+            .long("firewall")
+            .help("Tool to set up masquerade, forwarding and access control with. Used by wg-quick and Nix exports only, others set up firewall their own way")
+            .possible_values(["iptables", "nftables"])
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("route")
+            .long("route")
+            .help("Networks behind this peer, other peers route them through it")
+            .value_name("IP/MASK,IP/MASK")
+            .use_value_delimiter(true)
+            .validator(|f| IpNetwork::from_str(f)
+                .map(|_| ())
+                .map_err(|f| f.to_string())
+            )
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("route-masquerade")
+            .long("route-masquerade")
+            .help("Masquerade traffic from the network to routed networks through this interface")
+            .requires("route")
             .takes_value(true)
             .value_name("INTERFACE")
         )
+        .arg(clap::Arg::new("no-routes")
+            .long("no-routes")
+            .help("Stop routing networks through this peer")
+            .conflicts_with("route")
+            .takes_value(false)
+        )
+        .arg(clap::Arg::new("address")
+            .long("address")
+            .help("Static address of a peer, instead of one computed from its id")
+            .value_name("IP,IP")
+            .use_value_delimiter(true)
+            .validator(|f| IpAddr::from_str(f)
+                .map(|_| ())
+                .map_err(|f| f.to_string())
+            )
+            .takes_value(true)
+        )
+        .arg(clap::Arg::new("no-static-address")
+            .long("no-static-address")
+            .help("Drop static addresses, using ones computed from id")
+            .takes_value(false)
+        )
         .arg(clap::Arg::new("keepalive")
             .short('K')
             .long("keepalive")
@@ -317,6 +1359,18 @@ fn export_params<'a>(subcommand: clap::Command<'a>) -> clap::Command<'a> {
                 .help("Name of a new peer")
                 .required(true),
         )
+        .arg(
+            clap::Arg::new("template").long("template").help(
+                "For peers with only a public key, put a placeholder instead of the private key",
+            ),
+        )
+        .arg(
+            clap::Arg::new("endpoint-profile")
+                .long("endpoint-profile")
+                .help("Connect to peers using their endpoints with this name, instead of peer's usual profile")
+                .value_name("NAME")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::new("tunnel")
                 .short('T')
@@ -326,6 +1380,70 @@ fn export_params<'a>(subcommand: clap::Command<'a>) -> clap::Command<'a> {
         )
 }
 
+fn network_params<'a>(subcommand: clap::Command<'a>) -> clap::Command<'a> {
+    ula_params(subcommand, "add-ula")
+        .arg(
+            clap::Arg::new("add")
+                .long("add")
+                .help("Add a network, peers get addresses in it according to their ids")
+                .value_name("IP/MASK")
+                .use_value_delimiter(true)
+                .validator(|f| IpNetwork::from_str(f).map(|_| ()))
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("remove")
+                .long("remove")
+                .help("Remove a network, along with static addresses in it")
+                .value_name("IP/MASK")
+                .use_value_delimiter(true)
+                .validator(|f| IpNetwork::from_str(f).map(|_| ()))
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("centralized")
+                .long("centralized")
+                .help("Peers only connect to center peers")
+                .conflicts_with("no-centralized"),
+        )
+        .arg(
+            clap::Arg::new("no-centralized")
+                .long("no-centralized")
+                .help("Peers connect to each other directly"),
+        )
+        .arg(
+            clap::Arg::new("preshared-keys")
+                .long("preshared-keys")
+                .help("Generate a preshared key for every pair of peers")
+                .conflicts_with("no-preshared-keys"),
+        )
+        .arg(
+            clap::Arg::new("no-preshared-keys")
+                .long("no-preshared-keys")
+                .help("Drop preshared keys"),
+        )
+        .arg(
+            clap::Arg::new("primary-hub")
+                .long("primary-hub")
+                .help("Center all peers route the network through while it's up, instead of the one with the lowest id")
+                .value_name("HUB NAME")
+                .takes_value(true)
+                .conflicts_with("no-primary-hub"),
+        )
+        .arg(
+            clap::Arg::new("no-primary-hub")
+                .long("no-primary-hub")
+                .help("Route the network through the Center with the lowest id"),
+        )
+        .arg(
+            clap::Arg::new("rename")
+                .long("rename")
+                .help("New name of the network")
+                .value_name("NAME")
+                .takes_value(true),
+        )
+}
+
 fn main() {
     pretty_env_logger::init();
     // std::panic::set_hook(Box::new(panic_hook));
@@ -347,7 +1465,7 @@ fn main() {
                 .use_value_delimiter(false),
         )
         .subcommand(
-            clap::Command::new("init")
+            ula_params(clap::Command::new("init"), "ipv6")
                 .about("Initializes a config file")
                 .arg(clap::Arg::new("name").help("Network name").required(true))
                 .arg(
@@ -364,11 +1482,25 @@ fn main() {
                         .default_value("10.0.0.0/24")
                         .use_value_delimiter(false)
                         .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("preshared-keys")
+                        .long("preshared-keys")
+                        .help("Whether to generate a preshared key for every pair of peers")
+                        .takes_value(false),
                 ),
         )
         .subcommand(
             edit_params(clap::Command::new("add"))
                 .about("Adds a new peer to the network")
+                .arg(
+                    clap::Arg::new("public-key")
+                        .long("public-key")
+                        .help("Public key of a peer generating its own keys, no private key is stored")
+                        .value_name("KEY")
+                        .validator(check_public_key)
+                        .takes_value(true),
+                )
                 .arg(
                     clap::Arg::new("name")
                         .help("Name for a new peer")
@@ -376,6 +1508,17 @@ fn main() {
                 ),
         )
         .subcommand(clap::Command::new("list").about("Lists all added peers"))
+        .subcommand(
+            clap::Command::new("import")
+                .about("Imports peers from wg-quick configs, named after the files")
+                .arg(
+                    clap::Arg::new("files")
+                        .help("Configs to import")
+                        .value_name("FILE.conf")
+                        .multiple_values(true)
+                        .required(true),
+                ),
+        )
         .subcommand(
             edit_params(clap::Command::new("edit"))
                 .about("Edits existing peer")
@@ -408,6 +1551,120 @@ fn main() {
                 ),
         )
         .subcommand(clap::Command::new("hosts").about("Generates /etc/hosts for all peers"))
+        .subcommand(
+            clap::Command::new("failover")
+                .about(
+                    "Generates a script moving network routes of a peer to a backup hub, \
+                     when its primary hub goes down",
+                )
+                .arg(
+                    clap::Arg::new("name")
+                        .help("Name of a peer")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::new("stale")
+                        .long("stale")
+                        .help("Seconds since the last handshake after which a hub is considered down")
+                        .value_name("SECONDS")
+                        .validator(|v| u64::from_str(v).map(|_| ()))
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("keystore")
+                .about(
+                    "Manages encryption of private and preshared keys. \
+                     Passphrase is read from WG_BOND_PASSPHRASE, or asked for",
+                )
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("enable")
+                        .about("Encrypts secrets, new ones get encrypted without passphrase"),
+                )
+                .subcommand(clap::Command::new("disable").about("Decrypts secrets back")),
+        )
+        .subcommand(
+            clap::Command::new("acl")
+                .about(
+                    "Manages traffic Center peers forward between other peers. \
+                     Without rules everything is forwarded, otherwise only what rules allow",
+                )
+                .subcommand(
+                    clap::Command::new("add")
+                        .about("Allows peers of a group to connect to peers of another group")
+                        .arg(
+                            clap::Arg::new("from")
+                                .help("Group, peer name, or \"all\" connections come from")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::new("to")
+                                .help("Group, peer name, or \"all\" connections go to")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::new("service")
+                                .help("Allowed traffic, like tcp/22,443, udp/53 or icmp. Everything if not set")
+                                .multiple_values(true)
+                                .validator(|s| configs::acl::Service::from_str(s).map(|_| ())),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("rm")
+                        .about("Removes a rule")
+                        .arg(
+                            clap::Arg::new("number")
+                                .help("Number of the rule, as shown by list")
+                                .required(true),
+                        ),
+                )
+                .subcommand(clap::Command::new("list").about("Lists rules")),
+        )
+        .subcommand(
+            network_params(clap::Command::new("network"))
+                .about("Shows or changes network settings"),
+        )
+        .subcommand(
+            clap::Command::new("rotate")
+                .about("Replaces key of a peer, keeping the old public key in history")
+                .arg(
+                    clap::Arg::new("name")
+                        .help("Name of a peer")
+                        .required_unless_present("all"),
+                )
+                .arg(
+                    clap::Arg::new("all")
+                        .long("all")
+                        .help("Rotate keys of all peers, except ones with only a public key")
+                        .conflicts_with_all(&["name", "public-key"]),
+                )
+                .arg(
+                    clap::Arg::new("public-key")
+                        .long("public-key")
+                        .help("New public key of a peer generating its own keys")
+                        .value_name("KEY")
+                        .validator(check_public_key)
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("grace")
+                        .long("grace")
+                        .help(
+                            "Keep exporting the old key for given days, it keeps the routes \
+                             until the new one is confirmed",
+                        )
+                        .value_name("DAYS")
+                        .validator(|days| u64::from_str(days).map(|_| ()))
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("confirm")
+                        .long("confirm")
+                        .help("Confirm the new key works, ending grace period of old ones")
+                        .conflicts_with_all(&["public-key", "grace"]),
+                ),
+        )
         .subcommand(
             clap::Command::new("rm").about("Deletes a peer").arg(
                 clap::Arg::new("name")
@@ -415,17 +1672,105 @@ fn main() {
                     .required(true),
             ),
         )
+        .subcommand(
+            export_params(clap::Command::new("verify"))
+                .arg(
+                    clap::Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .help("Output of `wg showconf` or `wg show dump`, stdin is used by default")
+                        .value_name("FILE")
+                        .takes_value(true),
+                )
+                .about("Compares config of a peer with state of its running interface"),
+        )
         .subcommand(export_params(clap::Command::new("qr")).about("Generates QR code with config"))
         .subcommand(export_params(clap::Command::new("conf")).about("Generates wg-quick configs"))
+        .subcommand(
+            export_params(clap::Command::new("networkd"))
+                .arg(
+                    clap::Arg::new("secrets-prefix")
+                        .long("secrets-prefix")
+                        .help("Where secrets exported with `secrets` command are on the peer")
+                        .value_name("DIR")
+                        .default_value("/secrets")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Directory to write .netdev and .network files to, instead of printing them")
+                        .value_name("DIR")
+                        .takes_value(true),
+                )
+                .about("Generates systemd-networkd configs"),
+        )
+        .subcommand(
+            export_params(clap::Command::new("nm")).about("Generates NetworkManager keyfile"),
+        )
+        .subcommand(
+            export_params(clap::Command::new("openwrt"))
+                .arg(
+                    clap::Arg::new("firewall")
+                        .long("firewall")
+                        .help("Also set up a firewall zone, forwarding to the given zone if peer masquerades")
+                        .value_name("ZONE")
+                        .min_values(0)
+                        .default_missing_value("wan")
+                        .takes_value(true),
+                )
+                .about("Generates OpenWrt UCI commands"),
+        )
+        .subcommand(
+            export_params(clap::Command::new("routeros"))
+                .about("Generates MikroTik RouterOS script"),
+        )
+        .subcommand(
+            clap::Command::new("migrate")
+                .about("Upgrades config file to the current schema version")
+                .arg(
+                    clap::Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Only list migrations, don't save the config"),
+                ),
+        )
         .get_matches();
 
     let cfg_file = args.value_of("config").unwrap();
+    let _lock = match lock_config(cfg_file) {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     let mut net = if let Some(matches) = args.subcommand_matches("init") {
         command_init_config(matches)
     } else {
-        read_config(cfg_file).unwrap()
+        match read_config(cfg_file) {
+            Ok((net, applied)) => {
+                if let Some(matches) = args.subcommand_matches("migrate") {
+                    if applied.is_empty() {
+                        println!("Config is up to date");
+                    }
+                    for migration in applied.iter() {
+                        println!("{}", migration);
+                    }
+                    if matches.is_present("dry-run") {
+                        return;
+                    }
+                }
+                net
+            }
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
     };
+    net.update_preshared_keys();
 
     fn command_remove(
         cfg: &mut configs::WireguardNetworkInfo,
@@ -437,15 +1782,35 @@ fn main() {
             .iter()
             .position(|f| f.name == name)
             .ok_or("".to_string())?;
+
+        let id = cfg.peers[peer].id;
+        if let Some(client) = cfg.peers.iter().find(|p| {
+            p.flags
+                .iter()
+                .any(|f| matches!(f, configs::PeerFlag::UseGateway { peer, .. } if *peer == id))
+        }) {
+            Err(format!("Peer {} uses this peer as a gateway.", client.name))?;
+        }
+
         cfg.peers.remove(peer);
+        cfg.flags
+            .retain(|f| !matches!(f, configs::NetworkFlag::PrimaryHub { peer } if *peer == id));
+        for rule in cfg.acl.iter() {
+            if let Err(e) = cfg.check_acl_rule(rule) {
+                warn!("Access control rule {} matches nothing now: {}", rule, e);
+            }
+        }
         Ok(())
     }
 
     fn commands(net: &mut configs::WireguardNetworkInfo, args: &clap::ArgMatches) -> RVoid {
         match args.subcommand() {
             Some(("add", matches)) => command_new_peer(net, matches),
-            Some(("list", matches)) => command_list_peers(net, matches),
+            Some(("list", matches)) => command_list_peers(&with_secrets(net, false)?, matches),
+            Some(("import", matches)) => command_import(net, matches),
             Some(("edit", matches)) => command_edit_peer(net, matches),
+            Some(("rotate", matches)) => command_rotate(net, matches),
+            Some(("network", matches)) => command_network(net, matches),
             Some(("nix", matches)) => {
                 let conf = configs::nix::NixExportConfig {
                     use_keyfile: if matches.is_present("separate-secrets") {
@@ -460,19 +1825,43 @@ fn main() {
             }
             Some(("conf", matches)) => command_export::<ConfFile>(net, matches, ()),
             Some(("qr", matches)) => command_export::<QRConfig>(net, matches, ()),
+            Some(("networkd", matches)) => command_export_networkd(net, matches),
+            Some(("nm", matches)) => command_export::<NMConf>(net, matches, ()),
+            Some(("openwrt", matches)) => {
+                let conf = OpenWrtExportConfig {
+                    firewall_zone: matches.value_of("firewall").map(String::from),
+                };
+                command_export::<OpenWrtConf>(net, matches, conf)
+            }
+            Some(("routeros", matches)) => command_export::<RouterOSConf>(net, matches, ()),
             Some(("rm", matches)) => command_remove(net, matches),
+            Some(("verify", matches)) => command_verify(net, matches),
             Some(("hosts", _)) => {
-                println!("{}", export_hosts(net)?);
+                println!("{}", export_hosts(&with_secrets(net, false)?)?);
                 Ok(())
             }
             Some(("secrets", matches)) => {
-                command_export_secrets(net, matches).map_err(|e| e.to_string())
+                command_export_secrets(&with_secrets(net, true)?, matches)
+                    .map_err(|e| e.to_string())
+            }
+            Some(("keystore", matches)) => command_keystore(net, matches),
+            Some(("acl", matches)) => command_acl(net, matches),
+            Some(("failover", matches)) => {
+                let peer = net
+                    .by_name(matches.value_of("name").unwrap())
+                    .ok_or("No such peer")?;
+                let stale = matches
+                    .value_of("stale")
+                    .map(|v| u64::from_str(v).unwrap())
+                    .unwrap_or(configs::failover::DEFAULT_STALE_AFTER);
+                print!("{}", configs::failover::write_script(net, peer, stale)?);
+                Ok(())
             }
             Some(("nixops", _)) => {
                 println!(
                     "{}",
                     nixops::write_config(
-                        net,
+                        &with_secrets(net, false)?,
                         configs::nix::NixExportConfig {
                             use_keyfile: Some(KeyFileExportConfig {
                                 target_prefix: "/secrets".into()
@@ -486,10 +1875,24 @@ fn main() {
         }
     }
 
+    let name = net.name.clone();
     match commands(&mut net, &args) {
         Ok(()) => {
-            save_config(&net, cfg_file).unwrap();
+            net.update_preshared_keys();
+            if let Err(e) = net.seal_secrets().and_then(|_| save_config(&net, cfg_file)) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+            if net.name != name {
+                println!(
+                    "Network {} is renamed to {}, interfaces and secret files will use the new name.",
+                    name, net.name
+                );
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
         }
-        Err(e) => println!("{}", e),
     }
 }
@@ -1,19 +1,51 @@
-use base64;
-use rand_core::OsRng;
-use std::convert::TryFrom;
+use ipnetwork::Ipv6Network;
+use rand_core::{OsRng, RngCore};
+use std::net::Ipv6Addr;
 use x25519_dalek::{PublicKey, StaticSecret};
 
-fn read_key(from: &Vec<u8>) -> String {
-    String::from_utf8(from.to_owned())
-        .unwrap()
-        .trim_end()
-        .to_string()
-}
-
 pub fn gen_private_key() -> String {
     base64::encode(StaticSecret::new(OsRng).to_bytes())
 }
 
+/// Generates a symmetric key, same as `wg genpsk`
+pub fn gen_preshared_key() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    base64::encode(key)
+}
+
+/// Generates a random RFC 4193 unique local /64, with given subnet id inside of its /48
+pub fn gen_ula_prefix(subnet: u16) -> Ipv6Network {
+    let mut global_id = [0u8; 5];
+    OsRng.fill_bytes(&mut global_id);
+
+    let mut octets = [0u8; 16];
+    octets[0] = 0xfd;
+    octets[1..6].copy_from_slice(&global_id);
+    octets[6..8].copy_from_slice(&subnet.to_be_bytes());
+    Ipv6Network::new(Ipv6Addr::from(octets), 64).unwrap()
+}
+
+#[test]
+pub fn test_ula_prefix() {
+    let prefix = gen_ula_prefix(0x2a);
+    assert!(
+        Ipv6Network::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0), 8)
+            .unwrap()
+            .contains(prefix.ip())
+    );
+    assert_eq!(prefix.ip().segments()[3], 0x2a);
+    assert_eq!(prefix.prefix(), 64);
+    assert_ne!(prefix, gen_ula_prefix(0x2a));
+}
+
+#[test]
+pub fn test_preshared_key() {
+    let key = gen_preshared_key();
+    assert_eq!(base64::decode(&key).map(|k| k.len()), Ok(32));
+    assert_ne!(key, gen_preshared_key());
+}
+
 #[test]
 pub fn test_on_regular_keys() {
     assert_eq!(
@@ -42,7 +74,7 @@ pub fn gen_public_key(private_key: &str) -> Result<String, String> {
     let mut private_sized: [u8; 32] = [0; 32];
     private_sized.clone_from_slice(&private_base64[..]);
 
-    let secret = StaticSecret::try_from(private_sized).map_err(|_| "failed to convert keys?")?;
+    let secret = StaticSecret::from(private_sized);
 
     Ok(base64::encode(PublicKey::from(&secret).as_bytes()))
 }